@@ -0,0 +1,315 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures::future::BoxFuture;
+use tonic::{body::Body, codegen::http, TimeoutExpired};
+use tonic::transport::{Channel, Endpoint};
+use tower::Service;
+
+///
+/// Default interval between the health checks of the endpoints
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+///
+/// Default time limit for an endpoint to respond to a health check
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// Which of the endpoints are used for the API calls, when there are multiple endpoints (see `EmeraldConnBuilder::with_endpoints`).
+/// In all cases only the healthy endpoints are used, i.e., the ones that passed the last health check and had no connection failures since then.
+/// If all of them failed, all endpoints are used until one of them recovers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Selection {
+    ///
+    /// Use all healthy endpoints in turn, one call after another
+    #[default]
+    RoundRobin,
+    ///
+    /// Spread the calls across all healthy endpoints, preferring the less loaded ones.
+    /// For each call it picks two random endpoints and uses the one with fewer calls in progress (i.e., "power of two choices").
+    LeastLoaded,
+    ///
+    /// Use the first healthy endpoint in the order they're specified, and switch to the next ones only when it fails
+    Priority,
+    ///
+    /// Use the healthy endpoint which was the fastest to respond on the last health check
+    LowestLatency,
+}
+
+///
+/// How often and how long to check the endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            interval: DEFAULT_PROBE_INTERVAL,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        }
+    }
+}
+
+///
+/// Choose the endpoints to use, by the results of the health check
+///
+/// @param selection - selection policy
+/// @param latencies - for each endpoint, how long it took to connect to it, or `None` if it failed
+fn select(selection: Selection, latencies: &[Option<Duration>]) -> BTreeSet<usize> {
+    let healthy: Vec<(usize, Duration)> = latencies.iter().enumerate()
+        .filter_map(|(i, latency)| latency.map(|latency| (i, latency)))
+        .collect();
+    if healthy.is_empty() {
+        // nothing to choose from, so let the calls try any of them
+        return match selection {
+            Selection::RoundRobin | Selection::LeastLoaded => (0..latencies.len()).collect(),
+            Selection::Priority | Selection::LowestLatency => BTreeSet::from([0]),
+        };
+    }
+    match selection {
+        Selection::RoundRobin | Selection::LeastLoaded => healthy.iter().map(|(i, _)| *i).collect(),
+        Selection::Priority => BTreeSet::from([healthy[0].0]),
+        Selection::LowestLatency => healthy.iter()
+            .min_by_key(|(_, latency)| *latency)
+            .map(|(i, _)| BTreeSet::from([*i]))
+            .unwrap_or_default(),
+    }
+}
+
+///
+/// Connect to the endpoint and measure how long it takes. Returns `None` if it fails or takes longer than the timeout.
+async fn probe(endpoint: &Endpoint, timeout: Duration) -> Option<Duration> {
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, endpoint.connect()).await {
+        Ok(Ok(_)) => Some(started.elapsed()),
+        Ok(Err(e)) => {
+            tracing::warn!("Endpoint {} is not available: {}", endpoint.uri(), e);
+            None
+        }
+        Err(_) => {
+            tracing::warn!("Endpoint {} didn't respond in {:?}", endpoint.uri(), timeout);
+            None
+        }
+    }
+}
+
+///
+/// Check all endpoints at the same time
+pub(crate) async fn probe_all(endpoints: &[Endpoint], timeout: Duration) -> Vec<Option<Duration>> {
+    futures::future::join_all(endpoints.iter().map(|endpoint| probe(endpoint, timeout))).await
+}
+
+///
+/// Health of the endpoints, shared by all clones of a `BalancedChannel`
+struct BalanceState {
+    selection: Selection,
+    ///
+    /// Results of the last health check, for each endpoint. An endpoint is set to `None` also when a call to it fails to connect.
+    latencies: Mutex<Vec<Option<Duration>>>,
+    ///
+    /// Calls in progress, for each endpoint
+    in_flight: Vec<AtomicUsize>,
+    ///
+    /// Position of the next endpoint for `Selection::RoundRobin`
+    next: AtomicUsize,
+}
+
+impl BalanceState {
+    ///
+    /// Choose the endpoint for the next call
+    fn choose(&self) -> usize {
+        let selected: Vec<usize> = select(self.selection, &self.latencies.lock().unwrap()).into_iter().collect();
+        match selected.len() {
+            0 => 0,
+            1 => selected[0],
+            len => match self.selection {
+                Selection::LeastLoaded => {
+                    let random = getrandom::u64().unwrap_or_else(|_| self.next.fetch_add(1, Ordering::Relaxed) as u64) as usize;
+                    // two different endpoints
+                    let first = random % len;
+                    let second = (first + 1 + (random / len) % (len - 1)) % len;
+                    let (a, b) = (selected[first], selected[second]);
+                    if self.in_flight[b].load(Ordering::Relaxed) < self.in_flight[a].load(Ordering::Relaxed) { b } else { a }
+                }
+                _ => selected[self.next.fetch_add(1, Ordering::Relaxed) % len],
+            },
+        }
+    }
+
+    ///
+    /// Stop using the endpoint until it passes the next health check
+    fn eject(&self, index: usize) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies[index].take().is_some() {
+            tracing::warn!("Endpoint #{} failed, don't use it until the next health check", index);
+        }
+    }
+}
+
+///
+/// Counts a call in progress to an endpoint, until it's finished or dropped
+struct InFlight(Arc<BalanceState>, usize);
+
+impl InFlight {
+    fn new(state: Arc<BalanceState>, index: usize) -> Self {
+        state.in_flight[index].fetch_add(1, Ordering::Relaxed);
+        InFlight(state, index)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight[self.1].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+///
+/// A channel to multiple endpoints of the API, which sends each call to one of them according to the `Selection`.
+/// An endpoint which fails to connect is excluded right away, and it's used again once it passes a health check (see `maintain`).
+pub struct BalancedChannel {
+    channels: Vec<Channel>,
+    state: Arc<BalanceState>,
+    ///
+    /// The endpoint chosen for the next call, which is already ready
+    chosen: Option<usize>,
+}
+
+impl Clone for BalancedChannel {
+    fn clone(&self) -> Self {
+        // the readiness of the chosen channel is not cloned, so a clone chooses again
+        BalancedChannel {
+            channels: self.channels.clone(),
+            state: self.state.clone(),
+            chosen: None,
+        }
+    }
+}
+
+impl BalancedChannel {
+    ///
+    /// Make the channel and the task that keeps checking the endpoints. The task stops once all clones of the channel are dropped.
+    ///
+    /// @param endpoints - all endpoints, in the order of priority
+    /// @param latencies - the initial health of the endpoints
+    /// @param selection - which of the healthy endpoints to use
+    /// @param check - health check settings
+    pub(crate) fn new(endpoints: Vec<Endpoint>, latencies: Vec<Option<Duration>>, selection: Selection, check: HealthCheck) -> (Self, impl Future<Output = ()>) {
+        let state = Arc::new(BalanceState {
+            selection,
+            in_flight: endpoints.iter().map(|_| AtomicUsize::new(0)).collect(),
+            latencies: Mutex::new(latencies),
+            next: AtomicUsize::new(0),
+        });
+        let channel = BalancedChannel {
+            channels: endpoints.iter().map(|endpoint| endpoint.connect_lazy()).collect(),
+            state: state.clone(),
+            chosen: None,
+        };
+        (channel, maintain(endpoints, Arc::downgrade(&state), check))
+    }
+
+    ///
+    /// The channel to the endpoint which would be used for a call now
+    pub fn current(&self) -> Channel {
+        self.channels[self.state.choose()].clone()
+    }
+}
+
+///
+/// Check if the call failed because of the request timeout (see `EmeraldConnBuilder::with_request_timeout`)
+pub(crate) fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(current) = source {
+        if current.is::<TimeoutExpired>() {
+            return true;
+        }
+        source = current.source();
+    }
+    false
+}
+
+impl Service<http::Request<Body>> for BalancedChannel {
+    type Response = http::Response<Body>;
+    type Error = tonic::transport::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let index = *self.chosen.get_or_insert_with(|| self.state.choose());
+        let result = self.channels[index].poll_ready(cx);
+        if let Poll::Ready(Err(_)) = &result {
+            self.state.eject(index);
+            self.chosen = None;
+        }
+        result
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let index = self.chosen.take().expect("poll_ready must be called first");
+        let in_flight = InFlight::new(self.state.clone(), index);
+        let f = self.channels[index].call(req);
+        Box::pin(async move {
+            let result = f.await;
+            if let Err(e) = &result {
+                if !is_timeout(e) {
+                    in_flight.0.eject(index);
+                }
+            }
+            result
+        })
+    }
+}
+
+///
+/// Keep the health of the endpoints up to date: check all endpoints periodically,
+/// so the failed ones are not used, and they're used again once they recover.
+///
+/// Runs until the channel is dropped, i.e., until all connections and clients made from it are dropped.
+///
+/// @param endpoints - all endpoints, in the order of priority
+/// @param state - health of the endpoints, shared with the channel
+/// @param check - health check settings
+async fn maintain(endpoints: Vec<Endpoint>, state: Weak<BalanceState>, check: HealthCheck) {
+    loop {
+        tokio::time::sleep(check.interval).await;
+        if state.strong_count() == 0 {
+            return;
+        }
+        let latencies = probe_all(&endpoints, check.timeout).await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let mut current = state.latencies.lock().unwrap();
+        for (i, latency) in latencies.iter().enumerate() {
+            if latency.is_some() != current[i].is_some() {
+                tracing::debug!("Endpoint {} is {}", endpoints[i].uri(), if latency.is_some() { "available" } else { "not available" });
+            }
+        }
+        *current = latencies;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+    use crate::balance::{select, Selection};
+
+    #[test]
+    fn test_select_healthy() {
+        let latencies = vec![None, Some(Duration::from_millis(30)), Some(Duration::from_millis(10))];
+        assert_eq!(select(Selection::RoundRobin, &latencies), BTreeSet::from([1, 2]));
+        assert_eq!(select(Selection::LeastLoaded, &latencies), BTreeSet::from([1, 2]));
+        assert_eq!(select(Selection::Priority, &latencies), BTreeSet::from([1]));
+        assert_eq!(select(Selection::LowestLatency, &latencies), BTreeSet::from([2]));
+
+        // all failed
+        assert_eq!(select(Selection::RoundRobin, &[None, None]), BTreeSet::from([0, 1]));
+        assert_eq!(select(Selection::Priority, &[None, None]), BTreeSet::from([0]));
+    }
+}
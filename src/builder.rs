@@ -0,0 +1,440 @@
+use std::time::Duration;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri};
+use crate::balance::{self, BalancedChannel, HealthCheck, Selection};
+use crate::conn::{EmeraldConn, Transport};
+use crate::creds::Credentials;
+use crate::errors::Error;
+
+///
+/// Default URI of the Emerald API
+pub const DEFAULT_ENDPOINT: &str = "https://api.emrld.io";
+
+///
+/// Builds an `EmeraldConn` with custom transport options. Created with `EmeraldConn::builder()`.
+///
+/// Example:
+/// ```ignore
+/// let conn = EmeraldConn::builder()
+///     .with_credentials(Credentials::token(token))
+///     .with_connect_timeout(Duration::from_secs(5))
+///     .with_keepalive(Duration::from_secs(30), Duration::from_secs(10))
+///     .build()?;
+/// ```
+#[derive(Clone)]
+pub struct EmeraldConnBuilder {
+    /// URIs of the API, in the order of priority
+    endpoints: Vec<String>,
+    selection: Selection,
+    health_check: HealthCheck,
+    credentials: Credentials,
+    /// URI of a separate server for the Authenticate and Refresh calls
+    auth_endpoint: Option<String>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    /// HTTP/2 keepalive interval and timeout
+    keepalive: Option<(Duration, Duration)>,
+    concurrency_limit: Option<usize>,
+    stream_window_size: Option<u32>,
+    connection_window_size: Option<u32>,
+    user_agent: Option<String>,
+    /// trusted CA certificates in PEM format, in addition to the native roots if they're enabled
+    ca_certificates: Vec<Vec<u8>>,
+    native_roots: bool,
+    /// client certificate and key in PEM format
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    tls_domain: Option<String>,
+    plaintext: bool,
+}
+
+impl Default for EmeraldConnBuilder {
+    fn default() -> Self {
+        EmeraldConnBuilder {
+            endpoints: vec![DEFAULT_ENDPOINT.to_string()],
+            selection: Selection::default(),
+            health_check: HealthCheck::default(),
+            credentials: Credentials::None,
+            auth_endpoint: None,
+            connect_timeout: None,
+            request_timeout: None,
+            keepalive: None,
+            concurrency_limit: None,
+            stream_window_size: None,
+            connection_window_size: None,
+            user_agent: None,
+            ca_certificates: vec![],
+            native_roots: true,
+            identity: None,
+            tls_domain: None,
+            plaintext: false,
+        }
+    }
+}
+
+impl EmeraldConnBuilder {
+
+    ///
+    /// Set the URI of the API. Default is `DEFAULT_ENDPOINT`.
+    /// An `https` URI uses TLS, and an `http` URI is allowed only with `with_plaintext()`.
+    ///
+    /// @param uri - URI to connect to, e.g., "https://api.emrld.io" or "http://localhost:8080"
+    pub fn with_endpoint<S: ToString>(self, uri: S) -> Self {
+        EmeraldConnBuilder {
+            endpoints: vec![uri.to_string()],
+            ..self
+        }
+    }
+
+    ///
+    /// Use multiple endpoints of the API, e.g., in different regions. Which of them are used for each call is decided by the selection policy (see `with_selection`),
+    /// and the endpoints are checked periodically to stop using the failed ones (see `with_health_check`).
+    /// The calls go through the same `EmeraldConn`, so the clients and the JWT are shared between all endpoints.
+    /// All endpoints use the same options, e.g., the same TLS config.
+    ///
+    /// An endpoint is also excluded as soon as a call fails to connect to it, and it's used again once it passes a health check.
+    ///
+    /// NOTE: with more than one endpoint the connection must be made within a Tokio runtime
+    ///
+    /// @param uris - URIs of the API, in the order of priority
+    pub fn with_endpoints<S: ToString>(self, uris: Vec<S>) -> Self {
+        EmeraldConnBuilder {
+            endpoints: uris.iter().map(|uri| uri.to_string()).collect(),
+            ..self
+        }
+    }
+
+    ///
+    /// Set how to choose between multiple endpoints. Default is `Selection::RoundRobin`.
+    pub fn with_selection(self, selection: Selection) -> Self {
+        EmeraldConnBuilder {
+            selection,
+            ..self
+        }
+    }
+
+    ///
+    /// Set how often the endpoints are checked, when there are multiple endpoints. Defaults are `balance::DEFAULT_PROBE_INTERVAL` and `balance::DEFAULT_PROBE_TIMEOUT`.
+    /// An endpoint is checked by making a new connection to it, and it's considered as failed if that doesn't succeed in time.
+    ///
+    /// @param interval - time between the checks
+    /// @param timeout - time limit to connect to an endpoint
+    pub fn with_health_check(self, interval: Duration, timeout: Duration) -> Self {
+        EmeraldConnBuilder {
+            health_check: HealthCheck { interval, timeout },
+            ..self
+        }
+    }
+
+    ///
+    /// Set the credentials for the connection. Default is no credentials.
+    pub fn with_credentials(self, credentials: Credentials) -> Self {
+        EmeraldConnBuilder {
+            credentials,
+            ..self
+        }
+    }
+
+    ///
+    /// Make the Authenticate and Refresh calls to a different server, while the API calls still go to the main endpoints (see `EmeraldConn::with_auth_channel`).
+    /// The auth server is connected with the same options as the API, including the TLS config.
+    ///
+    /// @param uri - URI of the auth server, e.g., "https://auth.example.com"
+    pub fn with_auth_endpoint<S: ToString>(self, uri: S) -> Self {
+        EmeraldConnBuilder {
+            auth_endpoint: Some(uri.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the time limit to establish the connection
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        EmeraldConnBuilder {
+            connect_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the time limit for each API call. A call which is not finished in time fails with a `DeadlineExceeded` status.
+    /// NOTE: it applies to the whole call, so it should not be used for long streaming subscriptions
+    pub fn with_request_timeout(self, timeout: Duration) -> Self {
+        EmeraldConnBuilder {
+            request_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    ///
+    /// Send HTTP/2 pings to keep the connection alive and to detect a broken connection, even when there are no active calls.
+    ///
+    /// @param interval - how often to send a ping
+    /// @param timeout - how long to wait for the ping response before closing the connection
+    pub fn with_keepalive(self, interval: Duration, timeout: Duration) -> Self {
+        EmeraldConnBuilder {
+            keepalive: Some((interval, timeout)),
+            ..self
+        }
+    }
+
+    ///
+    /// Limit the number of calls in progress at the same time. Other calls wait until one of the current calls is finished.
+    pub fn with_concurrency_limit(self, limit: usize) -> Self {
+        EmeraldConnBuilder {
+            concurrency_limit: Some(limit),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the HTTP/2 flow control windows, e.g., larger ones for streaming a lot of data
+    ///
+    /// @param stream - initial window size of each call
+    /// @param connection - initial window size of the whole connection
+    pub fn with_window_sizes(self, stream: u32, connection: u32) -> Self {
+        EmeraldConnBuilder {
+            stream_window_size: Some(stream),
+            connection_window_size: Some(connection),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the `user-agent` header sent with each call
+    pub fn with_user_agent<S: ToString>(self, user_agent: S) -> Self {
+        EmeraldConnBuilder {
+            user_agent: Some(user_agent.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Trust the CA certificate(s) in addition to the native roots, e.g., an internal CA of a private network.
+    /// Can be called multiple times to trust multiple CAs.
+    ///
+    /// @param pem - one or more certificates in PEM format
+    pub fn with_ca_certificate<P: AsRef<[u8]>>(mut self, pem: P) -> Self {
+        self.ca_certificates.push(pem.as_ref().to_vec());
+        self
+    }
+
+    ///
+    /// Set whether to trust the CAs of the operating system. Default is `true`.
+    /// Disable it to trust only the certificates provided with `with_ca_certificate`, or when the system has no CA certificates at all (e.g., a minimal container).
+    pub fn with_native_roots(self, enabled: bool) -> Self {
+        EmeraldConnBuilder {
+            native_roots: enabled,
+            ..self
+        }
+    }
+
+    ///
+    /// Authenticate the client with a certificate (mutual TLS)
+    ///
+    /// @param cert_pem - client certificate chain in PEM format
+    /// @param key_pem - private key of the certificate in PEM format
+    pub fn with_client_identity<C: AsRef<[u8]>, K: AsRef<[u8]>>(self, cert_pem: C, key_pem: K) -> Self {
+        EmeraldConnBuilder {
+            identity: Some((cert_pem.as_ref().to_vec(), key_pem.as_ref().to_vec())),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the server name to send with SNI and to verify the server certificate against, instead of the host from the URI.
+    /// Useful to connect by an IP address, or through a proxy.
+    pub fn with_tls_domain<S: ToString>(self, domain: S) -> Self {
+        EmeraldConnBuilder {
+            tls_domain: Some(domain.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Allow a plaintext connection to an `http` URI, e.g., to a local server.
+    /// NOTE: the credentials and the JWT are sent unencrypted
+    pub fn with_plaintext(self) -> Self {
+        EmeraldConnBuilder {
+            plaintext: true,
+            ..self
+        }
+    }
+
+    fn has_tls_options(&self) -> bool {
+        !self.ca_certificates.is_empty() || !self.native_roots || self.identity.is_some() || self.tls_domain.is_some()
+    }
+
+    fn tls_config(&self) -> Result<ClientTlsConfig, Error> {
+        if !self.native_roots && self.ca_certificates.is_empty() {
+            return Err(Error::InvalidTls("No trusted CA, either native roots or a CA certificate is required".to_string()));
+        }
+        let mut tls = ClientTlsConfig::new();
+        if self.native_roots {
+            tls = tls.with_native_roots();
+        }
+        for pem in &self.ca_certificates {
+            tls = tls.ca_certificate(Certificate::from_pem(pem));
+        }
+        if let Some((cert, key)) = &self.identity {
+            tls = tls.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(domain) = &self.tls_domain {
+            tls = tls.domain_name(domain);
+        }
+        Ok(tls)
+    }
+
+    fn endpoint(&self, uri: &str) -> Result<Endpoint, Error> {
+        let uri: Uri = uri.parse()
+            .map_err(|e| Error::InvalidUri(format!("{}: {}", uri, e)))?;
+        let mut endpoint = Channel::builder(uri.clone());
+        match uri.scheme_str() {
+            Some("https") => {
+                if self.plaintext {
+                    return Err(Error::InvalidTls(format!("Plaintext mode cannot be used with {}", uri)));
+                }
+                endpoint = endpoint.tls_config(self.tls_config()?)
+                    .map_err(|e| Error::InvalidTls(e.to_string()))?;
+            }
+            Some("http") => {
+                if !self.plaintext {
+                    return Err(Error::InvalidTls(format!("{} is not encrypted, it must be enabled with `with_plaintext()`", uri)));
+                }
+                if self.has_tls_options() {
+                    return Err(Error::InvalidTls("TLS options cannot be used in plaintext mode".to_string()));
+                }
+            }
+            _ => return Err(Error::InvalidUri(format!("{}: must be an https or http URI", uri))),
+        }
+        if let Some(timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.request_timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        if let Some((interval, timeout)) = self.keepalive {
+            endpoint = endpoint.http2_keep_alive_interval(interval)
+                .keep_alive_timeout(timeout)
+                .keep_alive_while_idle(true);
+        }
+        if let Some(limit) = self.concurrency_limit {
+            endpoint = endpoint.concurrency_limit(limit);
+        }
+        endpoint = endpoint.initial_stream_window_size(self.stream_window_size)
+            .initial_connection_window_size(self.connection_window_size);
+        if let Some(user_agent) = &self.user_agent {
+            endpoint = endpoint.user_agent(user_agent.as_str())
+                .map_err(|e| Error::InvalidConfig(format!("User agent {}: {}", user_agent, e)))?;
+        }
+        Ok(endpoint)
+    }
+
+    fn endpoints(&self) -> Result<Vec<Endpoint>, Error> {
+        if self.endpoints.is_empty() {
+            return Err(Error::InvalidUri("No endpoints".to_string()));
+        }
+        self.endpoints.iter().map(|uri| self.endpoint(uri)).collect()
+    }
+
+    ///
+    /// A lazy channel to the auth server, if it's specified
+    fn auth_channel(&self) -> Result<Option<Channel>, Error> {
+        match &self.auth_endpoint {
+            Some(uri) => Ok(Some(self.endpoint(uri)?.connect_lazy())),
+            None => Ok(None),
+        }
+    }
+
+    fn with_auth_channel(conn: EmeraldConn, auth_channel: Option<Channel>) -> EmeraldConn {
+        match auth_channel {
+            Some(channel) => conn.with_auth_channel(channel),
+            None => conn,
+        }
+    }
+
+    ///
+    /// Make a channel balanced between the endpoints, with a background task that keeps checking their health
+    ///
+    /// @param latencies - the initial health of the endpoints
+    fn balanced(self, endpoints: Vec<Endpoint>, latencies: Vec<Option<Duration>>) -> Result<EmeraldConn, Error> {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| Error::InvalidConfig("Multiple endpoints can be used only within a Tokio runtime".to_string()))?;
+        let (channel, maintain) = BalancedChannel::new(endpoints, latencies, self.selection, self.health_check);
+        // it's not bound to the connection, because the clients made from it keep using the channel after the connection is dropped.
+        // instead, it stops itself once the channel is gone
+        runtime.spawn(maintain);
+        // the JWT is the same for all of them, so it's stored for the primary one
+        let primary = self.endpoints[0].clone();
+        Ok(EmeraldConn::with_endpoint(Transport::Balanced(channel), self.credentials, primary))
+    }
+
+    ///
+    /// Make a lazy connection, i.e., it's established on the first call. Fails only if the options are invalid.
+    pub fn build(self) -> Result<EmeraldConn, Error> {
+        let mut endpoints = self.endpoints()?;
+        let auth_channel = self.auth_channel()?;
+        let conn = if endpoints.len() > 1 {
+            // not checked yet, so all of them are considered as healthy
+            let latencies = vec![Some(Duration::ZERO); endpoints.len()];
+            self.balanced(endpoints, latencies)?
+        } else {
+            let channel = endpoints.remove(0).connect_lazy();
+            let endpoint = self.endpoints[0].clone();
+            EmeraldConn::with_endpoint(Transport::Single(channel), self.credentials, endpoint)
+        };
+        Ok(Self::with_auth_channel(conn, auth_channel))
+    }
+
+    ///
+    /// Make the connection and wait until it's established, so a wrong address or an unavailable server is detected right away.
+    /// With multiple endpoints, it checks all of them and fails only if none is available.
+    pub async fn connect(self) -> Result<EmeraldConn, Error> {
+        let mut endpoints = self.endpoints()?;
+        let auth_channel = self.auth_channel()?;
+        let conn = if endpoints.len() > 1 {
+            let latencies = balance::probe_all(&endpoints, self.health_check.timeout).await;
+            if latencies.iter().all(Option::is_none) {
+                return Err(Error::Transport("None of the endpoints is available".to_string()));
+            }
+            self.balanced(endpoints, latencies)?
+        } else {
+            let channel = endpoints.remove(0).connect().await?;
+            let endpoint = self.endpoints[0].clone();
+            EmeraldConn::with_endpoint(Transport::Single(channel), self.credentials, endpoint)
+        };
+        Ok(Self::with_auth_channel(conn, auth_channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conn::EmeraldConn;
+    use crate::errors::Error;
+
+    #[test]
+    fn test_invalid_options() {
+        let result = EmeraldConn::builder().with_endpoint("not a uri").build();
+        assert!(matches!(result, Err(Error::InvalidUri(_))));
+
+        let result = EmeraldConn::builder().with_user_agent("bad\nagent").build();
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+
+        let result = EmeraldConn::builder().with_endpoint("http://localhost:8080").build();
+        assert!(matches!(result, Err(Error::InvalidTls(_))));
+
+        let result = EmeraldConn::builder().with_plaintext().build();
+        assert!(matches!(result, Err(Error::InvalidTls(_))));
+
+        let result = EmeraldConn::builder().with_native_roots(false).build();
+        assert!(matches!(result, Err(Error::InvalidTls(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_eagerly() {
+        let builder = EmeraldConn::builder()
+            .with_endpoint("http://127.0.0.1:1")
+            .with_plaintext()
+            .with_connect_timeout(std::time::Duration::from_secs(1));
+        assert!(builder.clone().build().is_ok());
+        assert!(matches!(builder.connect().await, Err(Error::Transport(_))));
+    }
+}
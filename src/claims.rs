@@ -0,0 +1,159 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+///
+/// Claims from the payload of a JWT.
+///
+/// On the client side they are decoded only to know the details of the current authentication,
+/// and the signature is NOT verified; that's a job for the server (see `crate::verify`).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct JwtClaims {
+    /// Subject (`sub`), i.e., the user the JWT is issued for
+    pub subject: Option<String>,
+    /// Issue time (`iat`)
+    pub issued_at: Option<DateTime<Utc>>,
+    /// Expiration time (`exp`)
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Scopes granted to the JWT, from a space separated `scope` or a list in `scopes`
+    pub scopes: Vec<String>,
+    /// Capabilities granted to the JWT (`capabilities`)
+    pub capabilities: Vec<String>,
+}
+
+///
+/// The payload as it's encoded in JWT
+#[derive(Deserialize)]
+pub(crate) struct Payload {
+    sub: Option<String>,
+    iat: Option<i64>,
+    exp: Option<i64>,
+    scope: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+impl JwtClaims {
+    ///
+    /// Decode the claims from the JWT payload, without verifying the signature.
+    /// Returns `None` if the value is not a JWT or the payload cannot be parsed.
+    ///
+    /// @param jwt - JWT in the compact form, i.e., `header.payload.signature`
+    pub fn decode(jwt: &str) -> Option<Self> {
+        let mut parts = jwt.split('.');
+        let payload = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(_), Some(payload), Some(_), None) => payload,
+            _ => return None,
+        };
+        // some encoders keep the padding, even though it's not allowed by the spec
+        let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))
+            .map_err(|e| tracing::debug!("Invalid JWT encoding: {}", e))
+            .ok()?;
+        let payload: Payload = serde_json::from_slice(&payload)
+            .map_err(|e| tracing::debug!("Invalid JWT payload: {}", e))
+            .ok()?;
+        Some(payload.into())
+    }
+}
+
+impl From<Payload> for JwtClaims {
+    fn from(payload: Payload) -> Self {
+        let mut scopes: Vec<String> = payload.scope.iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .map(|scope| scope.to_string())
+            .collect();
+        scopes.extend(payload.scopes);
+
+        JwtClaims {
+            subject: payload.sub,
+            issued_at: payload.iat.and_then(|ts| DateTime::from_timestamp(ts, 0)),
+            expires_at: payload.exp.and_then(|ts| DateTime::from_timestamp(ts, 0)),
+            scopes,
+            capabilities: payload.capabilities,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use chrono::{DateTime, Utc};
+    use crate::claims::JwtClaims;
+    use crate::clock::ServerClock;
+    use crate::creds::JwtState;
+    use crate::proto::auth::AuthResponse;
+
+    ///
+    /// Make an unsigned JWT with the payload
+    fn make_jwt(payload: &str) -> String {
+        format!("{}.{}.signature",
+                URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+                URL_SAFE_NO_PAD.encode(payload))
+    }
+
+    #[test]
+    fn test_decode_claims() {
+        let jwt = make_jwt(r#"{"sub":"user_001","iat":1700000000,"exp":1700003600,"scope":"read write","capabilities":["admin"]}"#);
+        let claims = JwtClaims::decode(&jwt).unwrap();
+        assert_eq!(claims, JwtClaims {
+            subject: Some("user_001".to_string()),
+            issued_at: DateTime::from_timestamp(1700000000, 0),
+            expires_at: DateTime::from_timestamp(1700003600, 0),
+            scopes: vec!["read".to_string(), "write".to_string()],
+            capabilities: vec!["admin".to_string()],
+        });
+    }
+
+    #[test]
+    fn test_decode_scopes_list() {
+        let jwt = make_jwt(r#"{"scopes":["read"]}"#);
+        let claims = JwtClaims::decode(&jwt).unwrap();
+        assert_eq!(claims.scopes, vec!["read".to_string()]);
+        assert_eq!(claims.expires_at, None);
+    }
+
+    #[test]
+    fn test_expiration_from_claims() {
+        let jwt = make_jwt(r#"{"exp":1700003600}"#);
+        let claimed = DateTime::from_timestamp(1700003600, 0).unwrap();
+
+        // not provided in the response
+        let response = AuthResponse { access_token: jwt.clone(), ..Default::default() };
+        match JwtState::from_response(response, "secret_token", &ServerClock::default()) {
+            JwtState::Authenticated { expires_at, .. } => assert_eq!(expires_at, claimed),
+            _ => panic!("Not authenticated"),
+        }
+
+        // the response says the JWT is valid longer than it is
+        let response = AuthResponse { access_token: jwt, expires_at: 1700007200000, ..Default::default() };
+        match JwtState::from_response(response, "secret_token", &ServerClock::default()) {
+            JwtState::Authenticated { expires_at, .. } => assert_eq!(expires_at, claimed),
+            _ => panic!("Not authenticated"),
+        }
+    }
+
+    #[test]
+    fn test_expiration_by_server_clock() {
+        // neither the response nor the JWT tells the expiration, so it's a minute by the server clock
+        let clock = ServerClock::default().with_margin(std::time::Duration::from_secs(3600));
+        let response = AuthResponse { access_token: "jwt_001".to_string(), ..Default::default() };
+        match JwtState::from_response(response, "secret_token", &clock) {
+            JwtState::Authenticated { expires_at, issued_at, .. } => {
+                assert!(expires_at > Utc::now() + chrono::Duration::minutes(60));
+                assert!(issued_at > Utc::now() + chrono::Duration::minutes(59));
+            },
+            _ => panic!("Not authenticated"),
+        }
+    }
+
+    #[test]
+    fn test_ignore_non_jwt() {
+        assert_eq!(JwtClaims::decode("jwt_001"), None);
+        assert_eq!(JwtClaims::decode("a.b.c"), None);
+        assert_eq!(JwtClaims::decode(&make_jwt("not json")), None);
+    }
+}
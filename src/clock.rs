@@ -0,0 +1,170 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use tonic::metadata::MetadataMap;
+use crate::claims::JwtClaims;
+
+///
+/// A smaller difference between the server and the local clock is ignored, because the server time is known only with a precision of a second
+const MIN_CLOCK_SKEW: chrono::Duration = chrono::Duration::seconds(2);
+
+///
+/// A larger difference is ignored, because it's more likely a wrong server time than a wrong local clock
+const MAX_CLOCK_SKEW: chrono::Duration = chrono::Duration::days(1);
+
+///
+/// Tracks the difference between the server clock and the local clock, so the JWT expiration (which is set by the server clock) is checked correctly on a host with a drifting clock.
+#[derive(Default)]
+pub(crate) struct ServerClock {
+    ///
+    /// Server time minus local time, in milliseconds. Shared by the clocks with different margins for the same server.
+    offset: Arc<AtomicI64>,
+    ///
+    /// How much earlier than its expiration time a JWT is considered as expired
+    margin: chrono::Duration,
+}
+
+impl ServerClock {
+
+    ///
+    /// The time to check the JWT expiration against, i.e., the current server time plus the safety margin
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+            + chrono::Duration::milliseconds(self.offset.load(Ordering::Relaxed))
+            + self.margin
+    }
+
+    ///
+    /// Server time minus local time, as measured by the last auth response
+    pub fn offset(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.offset.load(Ordering::Relaxed))
+    }
+
+    ///
+    /// A clock for the same server with another safety margin. The measured offset is shared with this one.
+    ///
+    /// @param margin - how much earlier than its expiration time a JWT is considered as expired
+    pub fn with_margin(&self, margin: Duration) -> Self {
+        ServerClock {
+            offset: self.offset.clone(),
+            // it fails only for an absurdly large margin
+            margin: chrono::Duration::from_std(margin).unwrap_or(MAX_CLOCK_SKEW),
+        }
+    }
+
+    ///
+    /// Measure the offset from an auth response: by its `date` header, or by the issue time of the received JWT if there is no header.
+    /// The JWT may be issued before the request (e.g., reused by a token broker), so its issue time is used only if it's within the round trip of the request.
+    /// An offset larger than `MAX_CLOCK_SKEW` is ignored.
+    ///
+    /// @param metadata - response headers
+    /// @param jwt - the received JWT
+    /// @param sent_at - local time when the request was sent
+    pub fn observe(&self, metadata: &MetadataMap, jwt: &str, sent_at: DateTime<Utc>) {
+        let received_at = Utc::now();
+        // the server time is taken somewhere during the round trip, so compare it with the middle of it
+        let local_time = sent_at + (received_at - sent_at) / 2;
+        let date = metadata.get("date")
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+            .map(|date| date.with_timezone(&Utc));
+        let server_time = match date {
+            Some(date) => date,
+            None => {
+                let Some(issued_at) = JwtClaims::decode(jwt).and_then(|claims| claims.issued_at) else {
+                    return;
+                };
+                // it's in seconds, so allow for the rounding
+                if issued_at < sent_at - chrono::Duration::seconds(1) || issued_at > received_at + chrono::Duration::seconds(1) {
+                    tracing::debug!("JWT is not issued during the request, ignore its issue time");
+                    return;
+                }
+                issued_at
+            }
+        };
+        let mut offset = server_time - local_time;
+        if offset.abs() > MAX_CLOCK_SKEW {
+            tracing::warn!("Server time differs from the local clock by {}s, ignore it", offset.num_seconds());
+            return;
+        }
+        if offset.abs() < MIN_CLOCK_SKEW {
+            offset = chrono::Duration::zero();
+        } else {
+            tracing::warn!("Local clock differs from the server clock by {}s", offset.num_seconds());
+        }
+        self.offset.store(offset.num_milliseconds(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use chrono::{DateTime, Utc};
+    use tonic::metadata::MetadataMap;
+    use crate::clock::ServerClock;
+
+    #[test]
+    fn test_offset_from_date_header() {
+        let clock = ServerClock::default();
+        let mut metadata = MetadataMap::new();
+        let server_time = Utc::now() + chrono::Duration::hours(1);
+        metadata.insert("date", server_time.format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse().unwrap());
+        clock.observe(&metadata, "jwt_001", Utc::now());
+        assert!((clock.offset() - chrono::Duration::hours(1)).abs() < chrono::Duration::seconds(2));
+        assert!(clock.now() > Utc::now() + chrono::Duration::minutes(59));
+
+        // almost the same time
+        let mut metadata = MetadataMap::new();
+        metadata.insert("date", Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse().unwrap());
+        clock.observe(&metadata, "jwt_001", Utc::now());
+        assert_eq!(clock.offset(), chrono::Duration::zero());
+    }
+
+    #[test]
+    fn test_margin_shares_offset() {
+        let clock = ServerClock::default();
+        let with_margin = clock.with_margin(std::time::Duration::from_secs(600));
+        assert!(with_margin.now() > clock.now() + chrono::Duration::minutes(9));
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("date", (Utc::now() + chrono::Duration::hours(1)).format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse().unwrap());
+        clock.observe(&metadata, "jwt_001", Utc::now());
+        assert_eq!(with_margin.offset(), clock.offset());
+        assert!(with_margin.now() > Utc::now() + chrono::Duration::minutes(69));
+    }
+
+    #[test]
+    fn test_ignore_too_large_offset() {
+        let clock = ServerClock::default();
+        let mut metadata = MetadataMap::new();
+        let server_time = Utc::now() + chrono::Duration::days(3);
+        metadata.insert("date", server_time.format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse().unwrap());
+        clock.observe(&metadata, "jwt_001", Utc::now());
+        assert_eq!(clock.offset(), chrono::Duration::zero());
+    }
+
+    fn jwt_issued_at(issued_at: DateTime<Utc>) -> String {
+        format!("{}.{}.signature",
+                URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+                URL_SAFE_NO_PAD.encode(format!(r#"{{"iat":{}}}"#, issued_at.timestamp())))
+    }
+
+    #[test]
+    fn test_offset_from_issue_time() {
+        let clock = ServerClock::default();
+        let mut metadata = MetadataMap::new();
+        metadata.insert("date", (Utc::now() + chrono::Duration::hours(1)).format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse().unwrap());
+        clock.observe(&metadata, "jwt_001", Utc::now());
+        assert!(clock.offset() > chrono::Duration::minutes(59));
+
+        // a JWT issued during the request shows the clocks are the same
+        clock.observe(&MetadataMap::new(), &jwt_issued_at(Utc::now()), Utc::now());
+        assert_eq!(clock.offset(), chrono::Duration::zero());
+
+        // a JWT issued long before is not a measure of the current server time
+        clock.observe(&MetadataMap::new(), &jwt_issued_at(Utc::now() - chrono::Duration::minutes(10)), Utc::now());
+        assert_eq!(clock.offset(), chrono::Duration::zero());
+    }
+}
@@ -1,14 +1,119 @@
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use tonic::{body::Body, codegen::http, Status};
 use tonic::transport::{Channel, Uri};
-use crate::creds::{AuthLayer, AuthService, Credentials};
-use tonic::transport::ClientTlsConfig;
-use tower::ServiceBuilder;
+use crate::creds::{AuthLayer, AuthService, AuthState, Credentials, CredentialsEvent, CredentialsHandle};
+use tower::{Layer, Service, ServiceBuilder};
 use crate::errors::Error;
+use crate::store::TokenStore;
+use crate::claims::JwtClaims;
+use crate::builder::{EmeraldConnBuilder, DEFAULT_ENDPOINT};
+use crate::balance::{is_timeout, BalancedChannel};
+use crate::policy::AuthPolicy;
+use crate::retry::{RetryLayer, RetryPolicy, RetryService, RetryState};
+use crate::ratelimit::{RateBudget, RateLimitLayer, RateLimitPolicy, RateLimitService, RateLimitState};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+///
+/// The service used by the API clients, i.e., the gRPC channel with all the layers of the connection
+pub type ApiChannel = AuthService<RetryService<RateLimitService<DeadlineService<Transport>>>>;
+
+///
+/// The channel under all layers of the connection: to a single endpoint, or balanced between multiple endpoints (see `EmeraldConnBuilder::with_endpoints`)
+#[derive(Clone)]
+pub enum Transport {
+    Single(Channel),
+    Balanced(BalancedChannel),
+}
+
+impl Service<http::Request<Body>> for Transport {
+    type Response = http::Response<Body>;
+    type Error = tonic::transport::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            Transport::Single(channel) => channel.poll_ready(cx),
+            Transport::Balanced(channel) => channel.poll_ready(cx),
+        }
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        match self {
+            Transport::Single(channel) => Box::pin(channel.call(req)),
+            Transport::Balanced(channel) => channel.call(req),
+        }
+    }
+}
+
+///
+/// Fails a call with DEADLINE_EXCEEDED when it's not finished within the request timeout of the channel (see `EmeraldConnBuilder::with_request_timeout`).
+/// Without it, the timeout is an unknown transport error.
+#[derive(Clone)]
+pub struct DeadlineService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<http::Request<Body>> for DeadlineService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<B>>,
+    S::Future: Send + 'static,
+    S::Error: std::error::Error + 'static,
+    B: Default,
+{
+    type Response = http::Response<B>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let f = self.inner.call(req);
+        Box::pin(async move {
+            match f.await {
+                Err(err) if is_timeout(&err) => Ok(Status::deadline_exceeded(format!("Request timed out: {}", err)).into_http()),
+                result => result,
+            }
+        })
+    }
+}
+
+struct DeadlineLayer;
+
+impl<S> Layer<S> for DeadlineLayer {
+    type Service = DeadlineService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DeadlineService { inner }
+    }
+}
 
 #[derive(Clone)]
 pub struct EmeraldConn {
-    channel: Channel,
-    pub(crate) credentials: Arc<RwLock<Credentials>>
+    channel: Transport,
+    pub(crate) auth: Arc<AuthState>,
+    /// retries of the failed calls, if enabled
+    retry: Option<Arc<RetryState>>,
+    /// client-side rate limits, if enabled
+    rate_limit: Option<Arc<RateLimitState>>,
+    refresh_task: Option<Arc<BackgroundTask>>,
+    /// URI of the API, if known
+    endpoint: Option<String>,
+}
+
+///
+/// A background task of the connection (i.e., the JWT refresh), shared by all its clones. It's stopped when the last clone is dropped.
+struct BackgroundTask(JoinHandle<()>);
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl EmeraldConn {
@@ -20,20 +125,47 @@ impl EmeraldConn {
     /// @param channel - channel to use
     /// @param cred - credentials to use
     pub fn new(channel: Channel, cred: Credentials) -> Self {
+        Self::with_transport(Transport::Single(channel), cred)
+    }
+
+    pub(crate) fn with_transport(channel: Transport, cred: Credentials) -> Self {
         Self {
             channel,
-            credentials: Arc::new(RwLock::new(cred)),
+            auth: Arc::new(AuthState::new(cred)),
+            retry: None,
+            rate_limit: None,
+            refresh_task: None,
+            endpoint: None,
+        }
+    }
+
+    ///
+    /// Build a connection with custom transport options, see `EmeraldConnBuilder`
+    pub fn builder() -> EmeraldConnBuilder {
+        EmeraldConnBuilder::default()
+    }
+
+    pub(crate) fn with_endpoint(channel: Transport, cred: Credentials, endpoint: String) -> Self {
+        Self {
+            endpoint: Some(endpoint),
+            ..Self::with_transport(channel, cred)
         }
     }
 
     ///
     /// Get gRPC channel tp use for API call, with the credentials layer.
     ///
-    pub fn channel(&self) -> AuthService<Channel> {
-        let auth_layer = AuthLayer::new(self.credentials.clone());
+    pub fn channel(&self) -> ApiChannel {
+        let auth_layer = AuthLayer::new(self.auth.clone());
+        let retry_layer = RetryLayer::new(self.retry.clone());
+        // under the retries, so each attempt is limited
+        let rate_limit_layer = RateLimitLayer::new(self.rate_limit.clone());
 
         ServiceBuilder::new()
             .layer(auth_layer)
+            .layer(retry_layer)
+            .layer(rate_limit_layer)
+            .layer(DeadlineLayer)
             .service(self.channel.clone())
     }
 
@@ -42,39 +174,223 @@ impl EmeraldConn {
     ///
     /// @param cred - credentials to use
     pub fn connect(cred: Credentials) -> Self {
-        Self::connect_endpoint("https://api.emrld.io", cred).unwrap()
+        Self::connect_endpoint(DEFAULT_ENDPOINT, cred).unwrap()
     }
 
     ///
-    /// Lazily connect using the provided credentials to a non-default URI
+    /// Lazily connect using the provided credentials to a non-default URI. An `http` URI makes a plaintext connection.
+    /// To configure the connection options use `builder()` instead.
     ///
     /// @param uri - URI to connect to. Must be a valid URI, e.g., "https://api.emrld.io" or "http://localhost:8080"
     /// @param cred - credentials to use
     pub fn connect_endpoint<S: TryInto<Uri>>(uri: S, cred: Credentials) -> Result<Self, Error> {
-        let tls = ClientTlsConfig::new().with_native_roots();
-        let uri = uri.try_into().map_err(|_| Error::Transport("Invalid URI".to_string()))?;
-        let channel = Channel::builder(uri)
-            .tls_config(tls).expect("TLS cannot be configured")
-            .connect_lazy();
-        Ok(Self::new(channel, cred))
+        let uri: Uri = uri.try_into().map_err(|_| Error::InvalidUri("Invalid URI".to_string()))?;
+        let mut builder = Self::builder();
+        if uri.scheme_str() == Some("http") {
+            builder = builder.with_plaintext();
+        }
+        builder
+            .with_endpoint(uri)
+            .with_credentials(cred)
+            .build()
     }
 
     ///
     /// Set the credentials for this connection
-    /// NOTE: this must be called before trying to connect to an API. I.e., before `emerald_api::API_SERVICE::connect(emerald_conn)`
+    /// NOTE: this must be called before trying to connect to an API. I.e., before `emerald_api::API_SERVICE::connect(emerald_conn)`.
+    /// To change the credentials of a connection already in use, see `set_credentials`.
+    /// All other settings made before (token store, refresh-ahead time, auth policy, auth server, etc.) are kept, and the background refresh is restarted for the new credentials.
     ///
     /// @param cred - credentials to use
     pub fn with_credentials(self, cred: Credentials) -> Self {
-        Self::new(self.channel, cred)
+        let auth = self.auth.derive(cred);
+        self.with_auth_state(auth)
+    }
+
+    ///
+    /// Change the auth settings for this connection only. Its clones and the clients already created from it keep the current settings,
+    /// so it gets a new credentials state with the same credentials and the current JWT.
+    fn with_auth<F: FnOnce(&mut AuthState)>(self, configure: F) -> Self {
+        let mut auth = self.auth.derive(self.auth.get_credentials());
+        configure(&mut auth);
+        self.with_auth_state(auth)
+    }
+
+    ///
+    /// Use the new credentials state, and restart the background refresh for it
+    fn with_auth_state(self, auth: AuthState) -> Self {
+        let background_refresh = self.refresh_task.is_some();
+        let conn = Self {
+            auth: Arc::new(auth),
+            refresh_task: None,
+            ..self
+        };
+        if background_refresh {
+            conn.with_background_refresh()
+        } else {
+            conn
+        }
+    }
+
+    ///
+    /// Replace the credentials of the connection, including all its clones and all clients already created from it.
+    /// The current JWT is dropped, and the next call authenticates with the new credentials.
+    ///
+    /// @param cred - credentials to use
+    pub fn set_credentials(&self, cred: Credentials) {
+        self.auth.replace_credentials(cred);
+    }
+
+    ///
+    /// Save the received JWT to the store, and use the stored one on start instead of making a new authentication.
+    /// The JWT is stored for the endpoint and the secret, so it's never used with other credentials.
+    /// Clones of the connection made before keep the current store.
+    ///
+    /// @param store - store to use, e.g., `FileTokenStore`
+    pub fn with_token_store<T: TokenStore + 'static>(self, store: T) -> Self {
+        let endpoint = self.endpoint.clone().unwrap_or_default();
+        self.with_auth(|auth| auth.set_store(Arc::new(store), endpoint))
+    }
+
+    ///
+    /// Set how long before the expiration the JWT should be refreshed. Default is `creds::DEFAULT_REFRESH_AHEAD`.
+    /// The JWT is used for API calls only until that moment, and the next call refreshes it first.
+    /// A JWT which lives shorter than that is refreshed in the middle of its lifetime.
+    /// Clones of the connection made before keep the current setting.
+    ///
+    /// @param ahead - time before the JWT expiration
+    pub fn with_refresh_ahead(self, ahead: Duration) -> Self {
+        self.with_auth(|auth| auth.set_refresh_ahead(ahead))
+    }
+
+    ///
+    /// Make the Authenticate and Refresh calls through a separate channel, e.g., to a regional auth server or a local token broker, while the API calls still go to the main endpoint.
+    /// To connect the auth server with the same options as the API (TLS config, timeouts, etc.), use `EmeraldConnBuilder::with_auth_endpoint` instead.
+    /// Clones of the connection made before keep the current setting.
+    ///
+    /// @param channel - channel to the auth server
+    pub fn with_auth_channel(self, channel: Channel) -> Self {
+        self.with_auth(|auth| auth.set_auth_channel(channel))
+    }
+
+    ///
+    /// Set the time limit for each Authenticate or Refresh call, including the connection to the auth server.
+    /// A call which is not finished in time fails with DEADLINE_EXCEEDED. By default, there is no limit.
+    /// Clones of the connection made before keep the current setting.
+    ///
+    /// @param timeout - max time for an auth call
+    pub fn with_auth_timeout(self, timeout: Duration) -> Self {
+        self.with_auth(|auth| auth.set_auth_timeout(timeout))
+    }
+
+    ///
+    /// Consider the JWT as expired a bit earlier than its expiration time, to tolerate the clock drift and the network delays. Default is zero.
+    /// The difference between the local and the server clock is measured on each authentication and applied in addition to the margin (see `clock_offset`).
+    /// Clones of the connection made before keep the current margin.
+    ///
+    /// @param margin - time before the JWT expiration
+    pub fn with_clock_margin(self, margin: Duration) -> Self {
+        self.with_auth(|auth| auth.set_clock_margin(margin))
+    }
+
+    ///
+    /// Set which methods can be called without authentication. Default is `AuthPolicy::default()`, which includes the public methods of all enabled APIs.
+    /// A method with `AuthRequirement::Public` is called without a JWT, and a method with `AuthRequirement::Optional` gets the JWT only if the connection already has a valid one.
+    /// Neither of them makes an authentication.
+    /// Clones of the connection made before keep the current policy.
+    ///
+    /// @param policy - requirements for the methods
+    pub fn with_auth_policy(self, policy: AuthPolicy) -> Self {
+        self.with_auth(|auth| auth.set_policy(policy))
+    }
+
+    ///
+    /// Retry the calls which failed because of a temporary problem, e.g., UNAVAILABLE or DEADLINE_EXCEEDED, if the method is safe to call again (see `RetryPolicy`).
+    /// By default, the failed calls are not retried.
+    ///
+    /// @param policy - which calls to retry and how, e.g., `RetryPolicy::default()`
+    pub fn with_retry(self, policy: RetryPolicy) -> Self {
+        Self {
+            retry: Some(Arc::new(RetryState::new(policy))),
+            ..self
+        }
+    }
+
+    ///
+    /// Limit the rate of the calls to stay within the API quotas (see `RateLimitPolicy`). The limits are shared by all clones of the connection.
+    /// By default, there is no limit.
+    ///
+    /// @param policy - limits for the calls
+    pub fn with_rate_limit(self, policy: RateLimitPolicy) -> Self {
+        Self {
+            rate_limit: Some(Arc::new(RateLimitState::new(policy))),
+            ..self
+        }
+    }
+
+    ///
+    /// The current state of the rate limits, e.g., to monitor how close the client is to the limits or how much they were decreased by the server.
+    /// Empty if the connection has no rate limit.
+    pub fn rate_budget(&self) -> Vec<RateBudget> {
+        self.rate_limit.as_ref().map(|state| state.budget()).unwrap_or_default()
+    }
+
+    ///
+    /// Refresh the JWT in background before it expires (see `with_refresh_ahead`), so API calls don't have to wait for it.
+    /// The background task is stopped when the last clone of the connection is dropped.
+    /// NOTE: it must be called within a Tokio runtime
+    pub fn with_background_refresh(mut self) -> Self {
+        if self.refresh_task.is_none() {
+            let task = tokio::spawn(self.auth.clone().refresh_in_background(self.channel.clone()));
+            self.refresh_task = Some(Arc::new(BackgroundTask(task)));
+        }
+        self
+    }
+
+    ///
+    /// Subscribe to the changes of the credentials state, e.g., to know when the client is authenticated or the authentication is failed.
+    /// Events which happened before the subscription are not received.
+    pub fn subscribe(&self) -> broadcast::Receiver<CredentialsEvent> {
+        self.auth.subscribe()
     }
 
     pub fn get_credentials(&self) -> Credentials {
-        self.credentials.read().unwrap().clone()
+        self.auth.get_credentials()
+    }
+
+    ///
+    /// Make a handle with other credentials, to use them for individual requests over the same connection (see `CredentialsHandle::attach`).
+    /// The handle uses the same refresh-ahead time, token store, auth policy and auth server as the connection, but it's never refreshed in background.
+    ///
+    /// @param cred - credentials to use
+    pub fn credentials_handle(&self, cred: Credentials) -> CredentialsHandle {
+        CredentialsHandle::new(self.auth.derive(cred))
+    }
+
+    ///
+    /// The difference between the server clock and the local clock (i.e., server time minus local time) as measured on the last authentication.
+    /// A difference under 2 seconds is considered as zero.
+    pub fn clock_offset(&self) -> chrono::Duration {
+        self.auth.clock_offset()
+    }
+
+    ///
+    /// Claims of the JWT currently used by the connection, e.g., to know the user or the granted scopes.
+    /// Returns `None` until the connection is authenticated, or if the JWT cannot be decoded.
+    /// NOTE: the claims are decoded without verifying the signature
+    pub fn claims(&self) -> Option<JwtClaims> {
+        self.auth.get_credentials().claims()
     }
 }
 
+///
+/// The gRPC channel of the connection, without the credentials layer.
+/// With multiple endpoints, it's the channel to the endpoint which would be used for a call at this moment.
 impl Into<Channel> for &EmeraldConn {
      fn into(self) -> Channel {
-          self.channel.clone()
+          match &self.channel {
+               Transport::Single(channel) => channel.clone(),
+               Transport::Balanced(channel) => channel.current(),
+          }
      }
 }
\ No newline at end of file
@@ -1,5 +1,8 @@
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tonic::{
+    Code,
     Status,
     client::GrpcService,
     codegen::{http, StdError},
@@ -9,11 +12,37 @@ use tonic::{
 use http::Response;
 use std::task::{Context, Poll};
 use tower::{Service, Layer};
-use futures::future::BoxFuture;
-use crate::errors::Error;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use crate::errors::{CredentialsError, Error};
+use crate::replay::RequestBody;
+use crate::retry::status_code;
+use crate::provider::{CredentialsProvider, ProvidedCredentials};
+use crate::store::{store_key, StoredToken, TokenStore};
+use crate::secret::Secret;
+use crate::claims::JwtClaims;
+use crate::clock::ServerClock;
+use crate::policy::{AuthPolicy, AuthRequirement};
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use crate::proto::auth::{auth_client, AuthRequest, AuthResponse, RefreshRequest};
+use crate::proto::auth::auth_request::AuthType;
+use tokio::sync::{broadcast, Notify};
+
+///
+/// Default time before the JWT expiration when the client starts to refresh it
+pub const DEFAULT_REFRESH_AHEAD: Duration = Duration::from_secs(10);
+
+///
+/// Delay before the next attempt if the background refresh has failed
+const BACKGROUND_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+///
+/// Minimum time between two successful background refreshes
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// Maximum difference between the expiration time in the auth response and in the JWT claims that is considered as normal
+const EXPIRATION_MISMATCH: chrono::Duration = chrono::Duration::minutes(1);
 
 #[derive(Debug, Clone)]
 pub enum Credentials {
@@ -24,6 +53,71 @@ pub enum Credentials {
     ///
     /// Authenticated with a JWT token
     Token(JwtState),
+
+    ///
+    /// Authenticated with a fixed JWT issued elsewhere. It's sent as is, without exchanging or refreshing it.
+    StaticJwt {
+        /// JWT to put in the `Authorization` header
+        jwt: Secret,
+        /// Expiration time of the JWT, if known; after that moment calls fail without reaching the server
+        expires_at: Option<DateTime<Utc>>,
+    },
+
+    ///
+    /// Authenticated with a secret or a JWT given by a provider each time the client needs a new one
+    Provided {
+        provider: Arc<dyn CredentialsProvider>,
+        /// JWT received for the last provided secret, if any
+        state: Option<JwtState>,
+        /// what to request when authenticating with a provided secret
+        options: AuthOptions,
+    },
+}
+
+///
+/// Agent details sent by default on authentication
+pub const DEFAULT_AGENT: &str = concat!("emerald-api-rs/", env!("CARGO_PKG_VERSION"));
+
+///
+/// What the client requests when it exchanges a secret for a JWT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthOptions {
+    /// scopes to limit the JWT to; empty to get all scopes allowed for the secret
+    pub scopes: Vec<String>,
+    /// capabilities of the client
+    pub capabilities: Vec<String>,
+    /// identification of the client software, `DEFAULT_AGENT` by default
+    pub agent_details: Vec<String>,
+    /// fail with `CredentialsError::ScopesNotGranted` if the server grants fewer scopes than requested, instead of just notifying with `CredentialsEvent::ScopesNotGranted`
+    pub require_scopes: bool,
+}
+
+impl Default for AuthOptions {
+    fn default() -> Self {
+        AuthOptions {
+            scopes: vec![],
+            capabilities: vec![],
+            agent_details: vec![DEFAULT_AGENT.to_string()],
+            require_scopes: false,
+        }
+    }
+}
+
+impl AuthOptions {
+    ///
+    /// Find the requested scopes that are missing in the JWT. It's never known if the JWT cannot be decoded.
+    fn missing_scopes(&self, jwt: &Secret) -> Vec<String> {
+        if self.scopes.is_empty() {
+            return vec![];
+        }
+        let Some(claims) = JwtClaims::decode(jwt.expose()) else {
+            return vec![];
+        };
+        self.scopes.iter()
+            .filter(|scope| !claims.scopes.contains(scope))
+            .cloned()
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -33,15 +127,23 @@ pub enum JwtState {
     Initial{
         ///
         /// A secret token to exchange for a JWT
-        secret: String
+        secret: Secret,
+        /// what to request on authentication
+        options: AuthOptions,
     },
     Authenticated {
         /// JWT received to authenticate future calls
-        jwt: String,
+        jwt: Secret,
         /// Expiration time of the JWT token; client automatically refreshes the JWT before that moment
         expires_at: DateTime<Utc>,
+        /// when the JWT was issued, to know its lifetime
+        issued_at: DateTime<Utc>,
         /// a secret token that can be exchanged for another JWT before the expiration time
-        refresh: String,
+        refresh: Secret,
+        /// the original secret the JWT was received for; used to authenticate again if the refresh token is rejected
+        secret: Secret,
+        /// what was requested on authentication; used for the next authentication
+        options: AuthOptions,
     }
 }
 
@@ -50,9 +152,49 @@ impl JwtState {
     /// Create a new initial state of JWT auth
     ///
     /// @param secret - secret token for exchanging for a JWT token, e.g., an API token
-    pub fn new(secret: String) -> Self {
+    pub fn new<S: Into<Secret>>(secret: S) -> Self {
+        JwtState::Initial {
+            secret: secret.into(),
+            options: AuthOptions::default(),
+        }
+    }
+
+    ///
+    /// The secret the JWT is (or will be) received for
+    pub fn secret(&self) -> &Secret {
+        match self {
+            JwtState::Initial { secret, .. } => secret,
+            JwtState::Authenticated { secret, .. } => secret,
+        }
+    }
+
+    ///
+    /// What is requested on authentication
+    pub fn options(&self) -> &AuthOptions {
+        match self {
+            JwtState::Initial { options, .. } => options,
+            JwtState::Authenticated { options, .. } => options,
+        }
+    }
+
+    fn options_mut(&mut self) -> &mut AuthOptions {
+        match self {
+            JwtState::Initial { options, .. } => options,
+            JwtState::Authenticated { options, .. } => options,
+        }
+    }
+
+    fn with_options(mut self, options: AuthOptions) -> Self {
+        *self.options_mut() = options;
+        self
+    }
+
+    ///
+    /// Go back to the initial state, to authenticate again with the same secret
+    fn reset(&self) -> Self {
         JwtState::Initial {
-            secret
+            secret: self.secret().clone(),
+            options: self.options().clone(),
         }
     }
 }
@@ -72,28 +214,688 @@ impl Credentials {
     }
 
     ///
-    /// Authenticate using a predefined JWT token, i.e., by putting it in the `Authorization` header
+    /// Authenticate using a predefined JWT token, i.e., by putting it in the `Authorization` header.
+    /// The token is never refreshed, and once it's rejected by the server all calls fail with `CredentialsError::JwtRejected`.
+    /// If the JWT has an expiration time in its claims, it's used the same way as with `jwt_until`.
     pub fn jwt<S: ToString>(jwt: S) -> Self {
-        Credentials::Token(JwtState::new(jwt.to_string()))
+        let jwt = Secret::new(jwt.to_string());
+        let expires_at = JwtClaims::decode(jwt.expose()).and_then(|claims| claims.expires_at);
+        Credentials::StaticJwt {
+            jwt,
+            expires_at,
+        }
+    }
+
+    ///
+    /// Authenticate using a predefined JWT token with a known expiration time.
+    /// After that moment all calls fail with `CredentialsError::JwtExpired`.
+    pub fn jwt_until<S: ToString>(jwt: S, expires_at: DateTime<Utc>) -> Self {
+        Credentials::StaticJwt {
+            jwt: Secret::new(jwt.to_string()),
+            expires_at: Some(expires_at),
+        }
     }
 
     ///
     /// Authenticate using an API token
     pub fn token<S: ToString>(secret_token: S) -> Self {
-        Credentials::Token(JwtState::Initial { secret: secret_token.to_string() })
+        Credentials::Token(JwtState::new(secret_token.to_string()))
+    }
+
+    ///
+    /// Authenticate using a secret or a JWT from the provider, which is asked each time the client needs a new one.
+    /// See `crate::provider` for the standard providers.
+    pub fn provider<P: CredentialsProvider + 'static>(provider: P) -> Self {
+        Credentials::Provided {
+            provider: Arc::new(provider),
+            state: None,
+            options: AuthOptions::default(),
+        }
+    }
+
+    ///
+    /// Request a JWT limited to the specified scopes, e.g., a read-only access.
+    /// Applies only to credentials exchanged for a JWT, i.e., `token` and `provider`.
+    ///
+    /// @param scopes - scopes to request
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        if let Some(options) = self.options_mut() {
+            options.scopes = scopes;
+        }
+        self
+    }
+
+    ///
+    /// Fail the authentication with `CredentialsError::ScopesNotGranted` if the server doesn't grant all the requested scopes
+    pub fn require_scopes(mut self) -> Self {
+        if let Some(options) = self.options_mut() {
+            options.require_scopes = true;
+        }
+        self
+    }
+
+    ///
+    /// Tell the server the capabilities of the client
+    ///
+    /// @param capabilities - capabilities to send with the authentication
+    pub fn with_capabilities(mut self, capabilities: Vec<String>) -> Self {
+        if let Some(options) = self.options_mut() {
+            options.capabilities = capabilities;
+        }
+        self
+    }
+
+    ///
+    /// Identify the application using the client. It's sent to the server in addition to `DEFAULT_AGENT`.
+    ///
+    /// @param agent - name and version of the application, e.g., `my-app/1.0.0`
+    pub fn with_agent<S: ToString>(mut self, agent: S) -> Self {
+        if let Some(options) = self.options_mut() {
+            options.agent_details.insert(0, agent.to_string());
+        }
+        self
+    }
+
+    ///
+    /// Credentials with another secret, but requesting the same as these ones
+    pub(crate) fn with_secret(&self, secret: Secret) -> Credentials {
+        let options = match self {
+            Credentials::Token(state) => state.options().clone(),
+            Credentials::Provided { options, .. } => options.clone(),
+            Credentials::None | Credentials::StaticJwt { .. } => AuthOptions::default(),
+        };
+        Credentials::Token(JwtState::Initial { secret, options })
+    }
+
+    fn options_mut(&mut self) -> Option<&mut AuthOptions> {
+        match self {
+            Credentials::Token(state) => Some(state.options_mut()),
+            Credentials::Provided { options, .. } => Some(options),
+            Credentials::None | Credentials::StaticJwt { .. } => None,
+        }
+    }
+
+    ///
+    /// Claims of the current JWT, if there is one and it can be decoded. The claims are NOT verified.
+    pub fn claims(&self) -> Option<JwtClaims> {
+        let jwt = match self {
+            Credentials::StaticJwt { jwt, .. } => jwt,
+            _ => match self.jwt_state()? {
+                JwtState::Authenticated { jwt, .. } => jwt,
+                JwtState::Initial { .. } => return None,
+            },
+        };
+        JwtClaims::decode(jwt.expose())
+    }
+
+    ///
+    /// The current state of the JWT received in exchange for a secret, if the credentials use one
+    fn jwt_state(&self) -> Option<&JwtState> {
+        match self {
+            Credentials::Token(state) => Some(state),
+            Credentials::Provided { state, .. } => state.as_ref(),
+            Credentials::None | Credentials::StaticJwt { .. } => None,
+        }
+    }
+}
+
+///
+/// A change in the state of the credentials, see `EmeraldConn::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CredentialsEvent {
+    ///
+    /// A new JWT is received in exchange for the secret
+    Authenticated {
+        expires_at: DateTime<Utc>,
+    },
+    ///
+    /// The JWT is replaced with a new one using the refresh token
+    Refreshed {
+        expires_at: DateTime<Utc>,
+    },
+    ///
+    /// Failed to refresh the JWT. The client tries to authenticate again with the secret after that, if possible.
+    RefreshFailed {
+        message: String,
+    },
+    ///
+    /// The server denied the authentication, e.g., because of an invalid or revoked secret
+    Denied {
+        /// status code from the server, or 0 if denied with an error
+        status: u32,
+        deny_message: String,
+    },
+    ///
+    /// Failed to authenticate because of a different reason, e.g., the server is not available
+    Failed {
+        message: String,
+    },
+    ///
+    /// The server granted fewer scopes than requested with `Credentials::with_scopes`
+    ScopesNotGranted {
+        missing: Vec<String>,
+    },
+    ///
+    /// The JWT is expired without being replaced
+    Expired,
+    ///
+    /// The server rejected the JWT before its expiration time
+    Rejected,
+}
+
+///
+/// Maximum number of events kept for a subscriber which didn't read them yet
+const EVENTS_CAPACITY: usize = 32;
+
+///
+/// Where to get a new JWT from
+enum AuthSource {
+    ///
+    /// Exchange the secret, or refresh the current JWT
+    State(JwtState),
+    ///
+    /// Ask the provider first; the JWT state is reused only if the provided secret is the same
+    Provider(Arc<dyn CredentialsProvider>, Option<JwtState>, AuthOptions),
+}
+
+///
+/// Credentials shared between all clones of a connection and all the clients created from it.
+pub(crate) struct AuthState {
+    credentials: RwLock<Credentials>,
+    ///
+    /// Incremented each time the credentials are replaced, so an auth call started for the previous credentials doesn't update the new ones
+    generation: AtomicU64,
+    ///
+    /// Incremented each time the JWT is replaced, so its expiration is reported only once
+    jwt_version: AtomicU64,
+    ///
+    /// Version of the JWT its expiration is already reported for
+    expired_version: AtomicU64,
+    ///
+    /// An authentication (or refresh) currently in progress.
+    /// All requests that need a new JWT wait for it instead of making their own auth call.
+    pending: Mutex<Option<PendingAuth>>,
+    ///
+    /// Notified each time a new JWT is received
+    updated: Notify,
+    ///
+    /// How long before the expiration the JWT is considered as expired and must be refreshed (in milliseconds)
+    refresh_ahead: AtomicU64,
+    ///
+    /// Storage to save the received JWT, with the endpoint it's received from
+    store: RwLock<Option<(Arc<dyn TokenStore>, String)>>,
+    ///
+    /// Which methods need the JWT
+    policy: RwLock<Arc<AuthPolicy>>,
+    ///
+    /// A dedicated channel for Authenticate and Refresh calls. If not set, they go through the same channel as the API calls.
+    auth_channel: RwLock<Option<transport::Channel>>,
+    ///
+    /// Limit for an Authenticate or Refresh call, if any
+    auth_timeout: Option<Duration>,
+    ///
+    /// The server time, to check the JWT expiration
+    clock: Arc<ServerClock>,
+    events: broadcast::Sender<CredentialsEvent>,
+}
+
+///
+/// A single auth call shared between all requests waiting for it. The result is cloned to each of them.
+type PendingAuth = Shared<BoxFuture<'static, Result<Secret, Error>>>;
+
+impl AuthState {
+    pub fn new(credentials: Credentials) -> Self {
+        AuthState {
+            credentials: RwLock::new(credentials),
+            generation: AtomicU64::new(0),
+            jwt_version: AtomicU64::new(0),
+            expired_version: AtomicU64::new(u64::MAX),
+            pending: Mutex::new(None),
+            updated: Notify::new(),
+            refresh_ahead: AtomicU64::new(DEFAULT_REFRESH_AHEAD.as_millis() as u64),
+            store: RwLock::new(None),
+            policy: RwLock::new(Arc::new(AuthPolicy::default())),
+            auth_channel: RwLock::new(None),
+            auth_timeout: None,
+            clock: Arc::new(ServerClock::default()),
+            events: broadcast::Sender::new(EVENTS_CAPACITY),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CredentialsEvent> {
+        self.events.subscribe()
+    }
+
+    ///
+    /// A new state for other credentials, with the same refresh, store, policy and auth server settings as this one
+    pub fn derive(&self, credentials: Credentials) -> Self {
+        let mut state = AuthState::new(credentials);
+        // it's the same server, so the same clock
+        state.clock = self.clock.clone();
+        state.refresh_ahead.store(self.refresh_ahead.load(Ordering::Relaxed), Ordering::Relaxed);
+        *state.store.write().unwrap() = self.store.read().unwrap().clone();
+        *state.policy.write().unwrap() = self.policy.read().unwrap().clone();
+        *state.auth_channel.write().unwrap() = self.auth_channel.read().unwrap().clone();
+        state.auth_timeout = self.auth_timeout;
+        state
+    }
+
+    ///
+    /// Make the Authenticate and Refresh calls through the channel instead of the API channel
+    pub fn set_auth_channel(&mut self, channel: transport::Channel) {
+        *self.auth_channel.get_mut().unwrap() = Some(channel);
+    }
+
+    pub fn set_auth_timeout(&mut self, timeout: Duration) {
+        self.auth_timeout = Some(timeout);
+    }
+
+    pub fn set_clock_margin(&mut self, margin: Duration) {
+        self.clock = Arc::new(self.clock.with_margin(margin));
+    }
+
+    ///
+    /// Server time minus local time
+    pub fn clock_offset(&self) -> chrono::Duration {
+        self.clock.offset()
+    }
+
+    pub fn set_policy(&mut self, policy: AuthPolicy) {
+        *self.policy.get_mut().unwrap() = Arc::new(policy);
+    }
+
+    ///
+    /// How the method must be authenticated
+    ///
+    /// @param path - path of the gRPC call
+    pub fn requirement(&self, path: &str) -> AuthRequirement {
+        self.policy.read().unwrap().requirement(path)
+    }
+
+    ///
+    /// Save each received JWT to the store, and try to load it from there before making an authentication
+    pub fn set_store(&mut self, store: Arc<dyn TokenStore>, endpoint: String) {
+        *self.store.get_mut().unwrap() = Some((store, endpoint));
+    }
+
+    ///
+    /// Find a JWT previously received for the secret.
+    /// The store is accessed on a blocking thread, because it may read a file.
+    async fn load_stored(&self, initial: &JwtState) -> Option<JwtState> {
+        let (store, endpoint) = self.store.read().unwrap().clone()?;
+        let key = store_key(&endpoint, initial.secret().expose());
+        let token = tokio::task::spawn_blocking(move || store.load(&key)).await.ok()??;
+        tracing::trace!("Use JWT from the store");
+        let issued_at = JwtClaims::decode(&token.jwt)
+            .and_then(|claims| claims.issued_at)
+            .unwrap_or_else(|| self.clock.now())
+            .min(token.expires_at);
+        Some(JwtState::Authenticated {
+            jwt: Secret::new(token.jwt),
+            expires_at: token.expires_at,
+            issued_at,
+            refresh: Secret::new(token.refresh),
+            secret: initial.secret().clone(),
+            options: initial.options().clone(),
+        })
+    }
+
+    ///
+    /// Save the JWT to the store, if it's configured.
+    /// The store is accessed on a blocking thread, because it may write a file.
+    async fn save_stored(&self, jwt_state: &JwtState) {
+        let JwtState::Authenticated { jwt, expires_at, refresh, secret, .. } = jwt_state else {
+            return;
+        };
+        let Some((store, endpoint)) = self.store.read().unwrap().clone() else {
+            return;
+        };
+        let key = store_key(&endpoint, secret.expose());
+        let token = StoredToken {
+            jwt: jwt.expose().to_string(),
+            refresh: refresh.expose().to_string(),
+            expires_at: *expires_at,
+        };
+        if let Err(e) = tokio::task::spawn_blocking(move || store.save(&key, &token)).await {
+            tracing::warn!("Failed to save JWT to the store: {}", e);
+        }
+    }
+
+    ///
+    /// Use the new JWT for the next calls.
+    /// It's ignored if the credentials are replaced while the JWT was requested.
+    ///
+    /// @param jwt_state - the received JWT
+    /// @param generation - generation of the credentials the JWT is requested for
+    /// @return true if the JWT is accepted
+    fn accept(&self, jwt_state: JwtState, generation: u64) -> bool {
+        {
+            let mut credentials = self.credentials.write().unwrap();
+            // the generation is changed only under the same lock, so it cannot be replaced in the middle
+            if self.generation.load(Ordering::Acquire) != generation {
+                tracing::debug!("Credentials are replaced, ignore the received JWT");
+                return false;
+            }
+            match &mut *credentials {
+                Credentials::Provided { state, .. } => *state = Some(jwt_state),
+                Credentials::Token(state) => *state = jwt_state,
+                _ => {
+                    tracing::debug!("Credentials don't use JWT anymore, ignore the received JWT");
+                    return false;
+                }
+            }
+            self.jwt_version.fetch_add(1, Ordering::AcqRel);
+        }
+        self.updated.notify_waiters();
+        true
+    }
+
+    ///
+    /// Mark the JWT as not valid anymore, so the next call makes a new authentication.
+    /// Does nothing if the current JWT is already different, i.e., if it's already replaced by another call.
+    pub fn invalidate(&self, rejected: &Secret) {
+        let mut credentials = self.credentials.write().unwrap();
+        match &mut *credentials {
+            Credentials::Token(state) => {
+                if let JwtState::Authenticated { jwt, .. } = state {
+                    if jwt == rejected {
+                        *state = state.reset();
+                        let _ = self.events.send(CredentialsEvent::Rejected);
+                    }
+                }
+            }
+            Credentials::Provided { state, .. } => {
+                if let Some(JwtState::Authenticated { jwt, .. }) = state {
+                    if jwt == rejected {
+                        *state = None;
+                        let _ = self.events.send(CredentialsEvent::Rejected);
+                    }
+                }
+            }
+            Credentials::None | Credentials::StaticJwt { .. } => {}
+        }
+    }
+
+    ///
+    /// Check if the credentials are a fixed JWT, which cannot be replaced with a new one
+    pub fn is_static(&self) -> bool {
+        matches!(&*self.credentials.read().unwrap(), Credentials::StaticJwt { .. })
+    }
+
+    pub fn set_refresh_ahead(&mut self, ahead: Duration) {
+        *self.refresh_ahead.get_mut() = ahead.as_millis() as u64;
+    }
+
+    ///
+    /// The moment when the JWT must be refreshed, i.e., its expiration time minus the refresh-ahead window.
+    /// For a JWT that lives shorter than the window, it's the middle of its lifetime instead, so it's still used for some time before the next refresh.
+    fn refresh_at(&self, issued_at: DateTime<Utc>, expires_at: DateTime<Utc>) -> DateTime<Utc> {
+        let ahead = expires_at - chrono::Duration::milliseconds(self.refresh_ahead.load(Ordering::Relaxed) as i64);
+        ahead.max(issued_at + (expires_at - issued_at) / 2)
+    }
+
+    ///
+    /// Get the JWT from the state if it can still be used
+    fn active_jwt(&self, state: &JwtState) -> Option<Secret> {
+        if let JwtState::Authenticated { jwt, expires_at, issued_at, .. } = state {
+            if self.refresh_at(*issued_at, *expires_at) > self.clock.now() {
+                return Some(jwt.clone());
+            }
+            tracing::debug!("JWT token expires at {:?}", expires_at);
+            if *expires_at <= self.clock.now() {
+                self.report_expired();
+            }
+        }
+        None
+    }
+
+    ///
+    /// Send the `Expired` event, unless it's already sent for the current JWT.
+    fn report_expired(&self) {
+        let version = self.jwt_version.load(Ordering::Acquire);
+        if self.expired_version.swap(version, Ordering::AcqRel) != version {
+            let _ = self.events.send(CredentialsEvent::Expired);
+        }
+    }
+
+    ///
+    /// Get the current JWT if it's not expired yet, without making any authentication.
+    /// Unlike `jwt`, it may return a JWT within the refresh-ahead window.
+    pub fn cached_jwt(&self) -> Option<Secret> {
+        let (jwt, expires_at) = match &*self.credentials.read().unwrap() {
+            Credentials::None => return None,
+            Credentials::StaticJwt { jwt, expires_at } => (jwt.clone(), *expires_at),
+            credentials => match credentials.jwt_state() {
+                Some(JwtState::Authenticated { jwt, expires_at, .. }) => (jwt.clone(), Some(*expires_at)),
+                _ => return None,
+            },
+        };
+        if expires_at.is_some_and(|expires_at| expires_at <= self.clock.now()) {
+            return None;
+        }
+        Some(jwt)
+    }
+
+    pub fn get_credentials(&self) -> Credentials {
+        self.credentials.read().unwrap().clone()
+    }
+
+    ///
+    /// Use different credentials for the next calls.
+    /// An auth call already in progress is not used by the next calls, and its result is ignored.
+    pub fn replace_credentials(&self, credentials: Credentials) {
+        {
+            // lock the pending call first, so a new auth call never starts with the old credentials
+            let mut pending = self.pending.lock().unwrap();
+            let mut current = self.credentials.write().unwrap();
+            *current = credentials;
+            self.generation.fetch_add(1, Ordering::AcqRel);
+            self.jwt_version.fetch_add(1, Ordering::AcqRel);
+            *pending = None;
+        }
+        self.updated.notify_waiters();
+    }
+
+    ///
+    /// Get a JWT to use for an API call, authenticating or refreshing it if needed.
+    /// Returns `None` if the credentials don't need any authentication.
+    ///
+    /// Only one auth request is made at a time. If it's already in progress, the call waits for it and uses its result.
+    /// A failure is returned to all the waiting calls, and the next call makes a new attempt.
+    pub(crate) async fn jwt<S>(self: &Arc<Self>, inner: S) -> Result<Option<Secret>, Error>
+    where
+        S: GrpcService<Body> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<StdError>,
+        S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
+        <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
+    {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.as_ref() {
+                Some(current) => current.clone(),
+                None => {
+                    // check the current credentials only after getting the lock, because it may be just updated by the previous auth call
+                    let source = match &*self.credentials.read().unwrap() {
+                        Credentials::None => return Ok(None),
+                        Credentials::StaticJwt { jwt, expires_at } => {
+                            if expires_at.is_some_and(|expires_at| expires_at <= self.clock.now()) {
+                                self.report_expired();
+                                return Err(Error::Credentials(CredentialsError::JwtExpired));
+                            }
+                            return Ok(Some(jwt.clone()));
+                        }
+                        Credentials::Token(jwt_state) => {
+                            if let Some(jwt) = self.active_jwt(jwt_state) {
+                                return Ok(Some(jwt));
+                            }
+                            AuthSource::State(jwt_state.clone())
+                        }
+                        Credentials::Provided { provider, state, options } => {
+                            if let Some(jwt) = state.as_ref().and_then(|state| self.active_jwt(state)) {
+                                return Ok(Some(jwt));
+                            }
+                            AuthSource::Provider(provider.clone(), state.clone(), options.clone())
+                        }
+                    };
+                    let generation = self.generation.load(Ordering::Acquire);
+                    let started = Self::auth_call(Arc::downgrade(self), generation, self.events.clone(), source, inner);
+                    *pending = Some(started.clone());
+                    started
+                }
+            }
+        };
+
+        let result = pending.clone().await;
+
+        {
+            // whoever gets the result first makes the place for the next auth call
+            let mut current = self.pending.lock().unwrap();
+            if current.as_ref().is_some_and(|current| current.ptr_eq(&pending)) {
+                *current = None;
+            }
+        }
+
+        result.map(Some)
+    }
+
+    ///
+    /// Prepare an auth call for the current state, i.e., authenticate with the secret or refresh an expired JWT (see `exchange`).
+    /// The call goes through the dedicated auth channel if it's set, or through the inner service otherwise.
+    /// The received JWT is written to the shared credentials so it can be reused by other requests,
+    /// unless the credentials are replaced after the call is started, i.e., if `generation` is not current anymore.
+    fn auth_call<S>(state: Weak<AuthState>, generation: u64, events: broadcast::Sender<CredentialsEvent>, source: AuthSource, inner: S) -> PendingAuth
+    where
+        S: GrpcService<Body> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<StdError>,
+        S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
+        <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
+    {
+        let f = async move {
+            let jwt_state = match source {
+                AuthSource::State(jwt_state) => jwt_state,
+                AuthSource::Provider(provider, current, options) => match provider.credentials().await? {
+                    // a provided JWT is used as is, and the provider is asked again for the next call
+                    ProvidedCredentials::Jwt(jwt) => return Ok(jwt),
+                    ProvidedCredentials::Secret(secret) => match current {
+                        Some(current @ JwtState::Authenticated { .. }) if *current.secret() == secret => current.with_options(options),
+                        _ => JwtState::Initial { secret, options },
+                    },
+                },
+            };
+            let mut jwt_state = jwt_state;
+            if let (JwtState::Initial { .. }, Some(state)) = (&jwt_state, state.upgrade()) {
+                if let Some(stored) = state.load_stored(&jwt_state).await {
+                    if let Some(jwt) = state.active_jwt(&stored) {
+                        // the stored JWT is still valid
+                        state.accept(stored, generation);
+                        return Ok(jwt);
+                    }
+                    jwt_state = stored;
+                }
+            }
+            let (channel, timeout, clock) = match state.upgrade() {
+                Some(state) => (state.auth_channel.read().unwrap().clone(), state.auth_timeout, state.clock.clone()),
+                None => (None, None, Arc::new(ServerClock::default())),
+            };
+            let exchange = match channel {
+                Some(channel) => exchange(jwt_state, auth_client::AuthClient::new(channel), &clock, &events).boxed(),
+                None => exchange(jwt_state, auth_client::AuthClient::new(inner), &clock, &events).boxed(),
+            };
+            let jwt = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, exchange).await {
+                    Ok(jwt) => jwt,
+                    Err(_) => {
+                        let _ = events.send(CredentialsEvent::Failed { message: "Auth call timed out".to_string() });
+                        Err(Status::deadline_exceeded(format!("Auth call timed out after {:?}", timeout)).into())
+                    }
+                },
+                None => exchange.await,
+            };
+            let jwt_state = match jwt {
+                Ok(jwt_state) => jwt_state,
+                Err(err) => {
+                    tracing::warn!("Failed to authenticate: {:?}", err);
+                    return Err(err)
+                }
+            };
+            let JwtState::Authenticated { jwt, options, .. } = &jwt_state else {
+                tracing::warn!("Not a JWT");
+                return Err(Error::Credentials(CredentialsError::MalformedJwt))
+            };
+            let missing = options.missing_scopes(jwt);
+            if !missing.is_empty() {
+                tracing::warn!("Scopes not granted: {:?}", missing);
+                let _ = events.send(CredentialsEvent::ScopesNotGranted { missing: missing.clone() });
+                if options.require_scopes {
+                    return Err(Error::Credentials(CredentialsError::ScopesNotGranted(missing)))
+                }
+            }
+            let jwt = jwt.clone();
+            if let Some(state) = state.upgrade() {
+                if state.accept(jwt_state.clone(), generation) {
+                    state.save_stored(&jwt_state).await;
+                }
+            }
+            Ok(jwt)
+        };
+        f.boxed().shared()
+    }
+
+    ///
+    /// Keep refreshing the JWT before it expires, so API calls never wait for a refresh.
+    /// It doesn't make the initial authentication, but starts working once the JWT is received.
+    ///
+    /// Runs forever, and it's up to the caller to stop it.
+    pub(crate) async fn refresh_in_background<S>(self: Arc<Self>, inner: S)
+    where
+        S: GrpcService<Body> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<StdError>,
+        S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
+        <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
+    {
+        loop {
+            // start listening before checking the state, so an update in between is not missed
+            let updated = self.updated.notified();
+            let refresh_at = match self.credentials.read().unwrap().jwt_state() {
+                Some(JwtState::Authenticated { expires_at, issued_at, .. }) => Some(self.refresh_at(*issued_at, *expires_at)),
+                _ => None,
+            };
+            let Some(refresh_at) = refresh_at else {
+                updated.await;
+                continue;
+            };
+            let wait = (refresh_at - self.clock.now()).to_std().unwrap_or(Duration::ZERO);
+            tokio::select! {
+                _ = updated => continue,
+                _ = tokio::time::sleep(wait) => {}
+            }
+            tracing::trace!("Refreshing JWT in background");
+            match self.jwt(inner.clone()).await {
+                // even if the server gives a JWT which is about to expire, don't refresh it over and over
+                Ok(_) => tokio::time::sleep(MIN_REFRESH_INTERVAL).await,
+                Err(err) => {
+                    tracing::warn!("Failed to refresh JWT in background: {:?}", err);
+                    tokio::time::sleep(BACKGROUND_RETRY_DELAY).await;
+                }
+            }
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct AuthService<S> {
     inner: S,
-    credentials: Arc<RwLock<Credentials>>,
+    state: Arc<AuthState>,
 }
 
 impl<S> Service<http::Request<Body>> for AuthService<S>
 where
     S: GrpcService<Body> + Send + 'static + Clone,
     S::Future: Send + 'static,
-    S::Error: Into<Error> + 'static,
+    S::Error: Into<Error> + Into<StdError> + 'static,
     S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
     <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
 {
@@ -105,8 +907,12 @@ where
         self.inner.poll_ready(cx).map_err(Into::into)
     }
 
-    fn call(&mut self, mut req: http::Request<Body>) -> Self::Future {
-        let credentials_global = self.credentials.clone();
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        // credentials attached to the request take priority over the shared ones
+        let state = match req.extensions().get::<CredentialsHandle>() {
+            Some(handle) => handle.state.clone(),
+            None => self.state.clone(),
+        };
 
         // This is necessary because tonic internally uses `tower::buffer::Buffer`.
         // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
@@ -114,42 +920,48 @@ where
         let inner_clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, inner_clone);
 
-        // let f: dyn Future<Output = Result<Self::Response, Self::Error>> + Send =
-        let f= async move {
-            // it makes a copy of the credentials to verify if it can be used or should authenticate
-            // but if it needs an authentication (or refresh) it may cause a concurrent request because it's not locked for write
-            // usually it needs just one auth request at the start and it's not a big problem if fetched it twice from the server,
-            // but with a heavy-load app it may cause some performance issues
-            // TODO use a tokio lock with write lock on update, to avoid this
-            let credentials = credentials_global.read().unwrap().clone();
-            match credentials {
-                Credentials::None => inner.call(req).await.map_err(Into::into),
-                Credentials::Token(jwt_state) => {
-                    match jwt_state {
-                        JwtState::Initial { secret } => {
-                            // Authenticate and get JWT token
-                            let client = auth_client::AuthClient::new(inner.clone());
-                            let jwt = Self::authenticate(&secret, client).await;
-                            let _auth = Self::process_auth(&mut req, credentials_global.clone(), jwt).await.map_err(Error::from)?;
-                            inner.call(req).await.map_err(Into::into)
-                        }
-                        JwtState::Authenticated { jwt, refresh, expires_at } => {
-                            let is_active = expires_at > Utc::now();
-                            if is_active {
-                                Self::add_auth_header(&mut req, &jwt);
-                                inner.call(req).await.map_err(Into::into)
-                            } else {
-                                tracing::debug!("JWT token expired at {:?}", expires_at);
-                                // Authenticate and get JWT token
-                                let client = auth_client::AuthClient::new(inner.clone());
-                                let jwt = Self::refresh(&refresh, client).await;
-                                let _auth = Self::process_auth(&mut req, credentials_global.clone(), jwt).await.map_err(Error::from)?;
-                                inner.call(req).await.map_err(Into::into)
-                            }
-                        }
-                    }
-                }
+        let requirement = state.requirement(req.uri().path());
+
+        let f = async move {
+            let jwt = match requirement {
+                AuthRequirement::Required => state.jwt(inner.clone()).await?,
+                AuthRequirement::Optional => state.cached_jwt(),
+                AuthRequirement::Public => None,
+            };
+            let Some(jwt) = jwt else {
+                return inner.call(req).await.map_err(Into::into)
+            };
+
+            let (parts, body) = req.into_parts();
+            let (mut req, replay) = RequestBody::read(body).into_request(parts.clone());
+            add_auth_header(&mut req, &jwt)?;
+            let response = inner.call(req).await.map_err(Into::<Error>::into)?;
+
+            if status_code(&response) != Some(Code::Unauthenticated) {
+                return Ok(response)
             }
+            if state.is_static() {
+                return Err(Error::Credentials(CredentialsError::JwtRejected))
+            }
+            // The server may reject the JWT before its expiration (ex. if it's revoked),
+            // and in this case it makes a new one and sends the request again, but only once.
+            // That's possible only if the body is buffered and the server responded with just a status (which is the usual way to reject a call).
+            let Some(body) = replay else {
+                return Ok(response)
+            };
+            tracing::debug!("JWT rejected by the server, authenticating again");
+            state.invalidate(&jwt);
+            // a method with optional auth is just called again without the JWT
+            let jwt = match requirement {
+                AuthRequirement::Required => state.jwt(inner.clone()).await?,
+                AuthRequirement::Optional | AuthRequirement::Public => None,
+            };
+            let (mut req, _) = RequestBody::Buffered(body).into_request(parts);
+            if let Some(jwt) = jwt {
+                add_auth_header(&mut req, &jwt)?;
+            }
+            futures::future::poll_fn(|cx| inner.poll_ready(cx)).await.map_err(Into::<Error>::into)?;
+            inner.call(req).await.map_err(Into::into)
         };
 
         Box::pin(f)
@@ -157,110 +969,234 @@ where
 
 }
 
-impl<S> AuthService<S>
+fn add_auth_header(req: &mut http::Request<Body>, jwt: &Secret) -> Result<(), Error> {
+    let mut value: http::HeaderValue = format!("Bearer {}", jwt.expose()).parse()
+        .map_err(|_| Error::Credentials(CredentialsError::MalformedJwt))?;
+    // keeps it out of the HPACK compression table and of the debug output
+    value.set_sensitive(true);
+    req.headers_mut().insert("authorization", value);
+    Ok(())
+}
+
+///
+/// Get a new JWT for the state, i.e., authenticate with the secret or refresh the current JWT.
+/// If the refresh token is rejected, it makes one attempt to authenticate with the original secret.
+async fn exchange<S>(jwt_state: JwtState, client: auth_client::AuthClient<S>, clock: &ServerClock, events: &broadcast::Sender<CredentialsEvent>) -> Result<JwtState, Error>
 where
-    S: GrpcService<Body> + Send + 'static + Clone,
-    S::Future: Send + 'static,
-    S::Error: Into<Error> + 'static,
+    S: GrpcService<Body> + Clone,
+    S::Error: Into<StdError>,
     S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
-    <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send, {
-
-    fn add_auth_header(req: &mut http::Request<Body>, jwt: &str) {
-        req.headers_mut().insert(
-            "authorization",
-            format!("Bearer {}", jwt).parse().unwrap(),
-        );
-    }
-
-    async fn process_auth(req: &mut http::Request<Body>, credentials: Arc<RwLock<Credentials>>, jwt: Result<JwtState, Status>) -> Result<(), Status> {
-        match jwt {
-            Ok(jwt) => {
-                {
-                    // write the received JWT token to the global credentials to it can be reused by other requests
-                    let mut credentials = credentials.write().unwrap();
-                    *credentials = Credentials::Token(jwt.clone());
-                }
-                if let JwtState::Authenticated { jwt, .. } = &jwt {
-                    Self::add_auth_header(req, jwt);
-                } else {
-                    tracing::warn!("Not a JWT");
-                    return Err(Status::unauthenticated("Invalid JWT received from the server"));
+    <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
+{
+    match jwt_state {
+        JwtState::Initial { secret, options } => authenticate(&secret, &options, client, clock, events).await,
+        JwtState::Authenticated { refresh, secret, options, .. } => {
+            match self::refresh(&refresh, &secret, &options, client.clone(), clock, events).await {
+                Err(Error::Credentials(CredentialsError::RefreshRejected)) if !secret.expose().is_empty() => {
+                    tracing::debug!("Refresh token rejected, authenticating again");
+                    authenticate(&secret, &options, client, clock, events).await
                 }
-            }
-            Err(status) => {
-                tracing::warn!("Non-ok response on auth: {:?}", status);
-                return Err(status)
+                other => other,
             }
         }
-
-        Ok(())
     }
+}
 
-    async fn authenticate(token: &String, mut client: auth_client::AuthClient<S>) -> Result<JwtState, Status> {
-        tracing::trace!("Authenticating...");
+async fn authenticate<S>(token: &Secret, options: &AuthOptions, mut client: auth_client::AuthClient<S>, clock: &ServerClock, events: &broadcast::Sender<CredentialsEvent>) -> Result<JwtState, Error>
+where
+    S: GrpcService<Body>,
+    S::Error: Into<StdError>,
+    S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
+    <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
+{
+    if token.expose().is_empty() {
+        let _ = events.send(CredentialsEvent::Failed { message: "Missing secret".to_string() });
+        return Err(Error::Credentials(CredentialsError::MissingSecret))
+    }
 
-        let request = tonic::Request::new(AuthRequest {
-            auth_secret: token.clone(),
-            ..Default::default()
-        });
+    tracing::trace!("Authenticating...");
 
-        let response = client.authenticate(request).await?;
-        let response = response.into_inner();
+    let request = tonic::Request::new(AuthRequest {
+        auth_type: Some(AuthType::AuthSecret(token.expose().to_string())),
+        scopes: options.scopes.clone(),
+        capabilities: options.capabilities.clone(),
+        agent_details: options.agent_details.clone(),
+        ..Default::default()
+    });
 
-        if response.status != 0 {
-            return Err(Status::unauthenticated(format!("{}: {}", response.status, response.deny_message)));
+    let sent_at = Utc::now();
+    let response = match client.authenticate(request).await {
+        Ok(response) => {
+            clock.observe(response.metadata(), &response.get_ref().access_token, sent_at);
+            response.into_inner()
         }
+        Err(status) => {
+            return match status.code() {
+                Code::Unauthenticated | Code::PermissionDenied => {
+                    let _ = events.send(CredentialsEvent::Denied { status: 0, deny_message: status.message().to_string() });
+                    Err(Error::Credentials(CredentialsError::Denied { status: 0, deny_message: status.message().to_string() }))
+                }
+                _ => {
+                    let _ = events.send(CredentialsEvent::Failed { message: status.to_string() });
+                    Err(status.into())
+                }
+            }
+        }
+    };
 
-        tracing::trace!("Authenticated with JWT");
-
-        Ok(JwtState::from(response))
+    if response.status != 0 {
+        let _ = events.send(CredentialsEvent::Denied { status: response.status, deny_message: response.deny_message.clone() });
+        return Err(Error::Credentials(CredentialsError::Denied { status: response.status, deny_message: response.deny_message }));
+    }
+    if response.access_token.is_empty() {
+        let _ = events.send(CredentialsEvent::Failed { message: "No JWT in the response".to_string() });
+        return Err(Error::Credentials(CredentialsError::MalformedJwt));
     }
 
-    async fn refresh(token: &String, mut client: auth_client::AuthClient<S>) -> Result<JwtState, Status> {
-        tracing::trace!("Refreshing the token...");
+    tracing::trace!("Authenticated with JWT");
 
-        let request = tonic::Request::new(RefreshRequest {
-            refresh_token: token.clone(),
-            ..Default::default()
-        });
+    let jwt_state = JwtState::from_response(response, token.clone(), clock).with_options(options.clone());
+    if let JwtState::Authenticated { expires_at, .. } = &jwt_state {
+        let _ = events.send(CredentialsEvent::Authenticated { expires_at: *expires_at });
+    }
+    Ok(jwt_state)
+}
 
-        let response = client.refresh(request).await?;
-        let response = response.into_inner();
+async fn refresh<S>(token: &Secret, secret: &Secret, options: &AuthOptions, mut client: auth_client::AuthClient<S>, clock: &ServerClock, events: &broadcast::Sender<CredentialsEvent>) -> Result<JwtState, Error>
+where
+    S: GrpcService<Body>,
+    S::Error: Into<StdError>,
+    S::ResponseBody: transport::Body<Data = Bytes> + Send + 'static,
+    <S::ResponseBody as transport::Body>::Error: Into<StdError> + Send,
+{
+    tracing::trace!("Refreshing the token...");
 
-        if response.status != 0 {
-            return Err(Status::unauthenticated(format!("Status: {}", response.status)));
-        }
+    let request = tonic::Request::new(RefreshRequest {
+        refresh_token: token.expose().to_string(),
+        ..Default::default()
+    });
 
-        tracing::trace!("Refreshed the JWT");
+    let sent_at = Utc::now();
+    let response = match client.refresh(request).await {
+        Ok(response) => {
+            clock.observe(response.metadata(), &response.get_ref().access_token, sent_at);
+            response.into_inner()
+        }
+        Err(status) => {
+            let _ = events.send(CredentialsEvent::RefreshFailed { message: status.to_string() });
+            return match status.code() {
+                Code::Unauthenticated | Code::PermissionDenied => Err(Error::Credentials(CredentialsError::RefreshRejected)),
+                _ => Err(status.into()),
+            }
+        }
+    };
 
-        Ok(JwtState::from(response))
+    if response.status != 0 {
+        let _ = events.send(CredentialsEvent::RefreshFailed { message: format!("Status: {}", response.status) });
+        return Err(Error::Credentials(CredentialsError::RefreshRejected));
     }
+    if response.access_token.is_empty() {
+        let _ = events.send(CredentialsEvent::RefreshFailed { message: "No JWT in the response".to_string() });
+        return Err(Error::Credentials(CredentialsError::MalformedJwt));
+    }
+
+    tracing::trace!("Refreshed the JWT");
 
+    let jwt_state = JwtState::from_response(response, secret.clone(), clock).with_options(options.clone());
+    if let JwtState::Authenticated { expires_at, .. } = &jwt_state {
+        let _ = events.send(CredentialsEvent::Refreshed { expires_at: *expires_at });
+    }
+    Ok(jwt_state)
 }
 
-impl From<AuthResponse> for JwtState {
-    fn from(response: AuthResponse) -> Self {
+impl JwtState {
+    ///
+    /// Create an authenticated state from the server response.
+    /// The expiration time is cross-checked with the `exp` claim of the JWT, and the earliest of them is used.
+    ///
+    /// @param response - a successful response to an authentication or refresh request
+    /// @param secret - the secret the JWT was originally received for
+    /// @param clock - the server time, in case the response doesn't tell when the JWT is issued or expires
+    pub(crate) fn from_response<S: Into<Secret>>(response: AuthResponse, secret: S, clock: &ServerClock) -> Self {
+        let reported = DateTime::from_timestamp_millis(response.expires_at as i64)
+            .filter(|_| response.expires_at > 0);
+        let claims = JwtClaims::decode(&response.access_token);
+        let claimed = claims.as_ref().and_then(|claims| claims.expires_at);
+        let expires_at = match (reported, claimed) {
+            (Some(reported), Some(claimed)) => {
+                if (reported - claimed).abs() > EXPIRATION_MISMATCH {
+                    tracing::warn!("JWT expires at {} but the server reported {}", claimed, reported);
+                }
+                reported.min(claimed)
+            }
+            (Some(expires_at), None) | (None, Some(expires_at)) => expires_at,
+            // an invalid ts will not happen unless there is a major bug in the server,
+            // but if it happens we consider that the JWT is valid for at least a minute
+            (None, None) => clock.now() + chrono::Duration::minutes(1),
+        };
+        let issued_at = claims.and_then(|claims| claims.issued_at)
+            .unwrap_or_else(|| clock.now())
+            .min(expires_at);
         JwtState::Authenticated {
-            jwt: response.access_token.clone(),
-            refresh: response.refresh_token.clone(),
-            expires_at: DateTime::from_timestamp_millis(response.expires_at as i64)
-                // an invalid ts will not happen unless there is a major bug in the server,
-                // but if it happens we consider that the JWT is valid for at least a minute
-                .unwrap_or(Utc::now() + chrono::Duration::minutes(1)),
+            jwt: Secret::new(response.access_token),
+            refresh: Secret::new(response.refresh_token),
+            expires_at,
+            issued_at,
+            secret: secret.into(),
+            options: AuthOptions::default(),
+        }
+    }
+}
+
+///
+/// Credentials to use for individual requests instead of the credentials of the connection, e.g., to make calls on behalf of different users over the same connection.
+/// Each handle keeps its own JWT, and it's shared between all clones of the handle.
+///
+/// Example:
+/// ```ignore
+/// let tenant = conn.credentials_handle(Credentials::token(tenant_token));
+/// let response = client.get_rates(tenant.attach(tonic::Request::new(request))).await?;
+/// ```
+#[derive(Clone)]
+pub struct CredentialsHandle {
+    state: Arc<AuthState>,
+}
+
+impl CredentialsHandle {
+    pub(crate) fn new(state: AuthState) -> Self {
+        CredentialsHandle {
+            state: Arc::new(state)
         }
     }
+
+    ///
+    /// Make the request use these credentials
+    pub fn attach<T>(&self, mut request: tonic::Request<T>) -> tonic::Request<T> {
+        request.extensions_mut().insert(self.clone());
+        request
+    }
+
+    ///
+    /// Subscribe to the changes of the credentials state, see `EmeraldConn::subscribe`
+    pub fn subscribe(&self) -> broadcast::Receiver<CredentialsEvent> {
+        self.state.subscribe()
+    }
+
+    pub fn get_credentials(&self) -> Credentials {
+        self.state.get_credentials()
+    }
 }
 
 ///
 /// An Authentication Layer for the Tokio Tower
 pub(crate) struct AuthLayer {
-    credentials: Arc<RwLock<Credentials>>,
+    state: Arc<AuthState>,
 }
 
 impl AuthLayer {
-    pub fn new(credentials: Arc<RwLock<Credentials>>) -> Self {
+    pub fn new(state: Arc<AuthState>) -> Self {
         AuthLayer {
-            credentials
+            state
         }
     }
 }
@@ -278,7 +1214,7 @@ where
     fn layer(&self, service: S) -> Self::Service {
         AuthService {
             inner: service,
-            credentials: self.credentials.clone(),
+            state: self.state.clone(),
         }
     }
 }
@@ -4,6 +4,34 @@ use std::fmt::{Display, Formatter};
 pub enum Error {
     #[cfg(feature = "client")]
     Credentials(CredentialsError),
+    ///
+    /// A key to verify JWT cannot be used
+    #[cfg(feature = "server-auth")]
+    InvalidKey(String),
+    ///
+    /// The URI to connect to is not valid
+    #[cfg(feature = "client")]
+    InvalidUri(String),
+    ///
+    /// TLS cannot be configured with the provided options
+    #[cfg(feature = "client")]
+    InvalidTls(String),
+    ///
+    /// Other connection option is not valid, e.g., a user agent with characters not allowed in a header
+    #[cfg(feature = "client")]
+    InvalidConfig(String),
+    ///
+    /// The call is rejected by the client-side rate limit, because too many calls are already waiting or it would wait too long
+    #[cfg(feature = "client")]
+    RateLimited(String),
+    ///
+    /// The API token is not found among the tokens of the user
+    #[cfg(feature = "client")]
+    TokenNotFound(String),
+    ///
+    /// A value passed to a method cannot be sent to the API, e.g., an expiration time before 1970
+    #[cfg(feature = "client")]
+    InvalidArgument(String),
     Transport(String)
 }
 
@@ -11,7 +39,21 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             #[cfg(feature = "client")]
-            Error::Credentials(e) => write!(f, "Credentials error: {:?}", e),
+            Error::Credentials(e) => write!(f, "Credentials error: {}", e),
+            #[cfg(feature = "server-auth")]
+            Error::InvalidKey(e) => write!(f, "Invalid key: {}", e),
+            #[cfg(feature = "client")]
+            Error::InvalidUri(e) => write!(f, "Invalid URI: {}", e),
+            #[cfg(feature = "client")]
+            Error::InvalidTls(e) => write!(f, "Invalid TLS config: {}", e),
+            #[cfg(feature = "client")]
+            Error::InvalidConfig(e) => write!(f, "Invalid config: {}", e),
+            #[cfg(feature = "client")]
+            Error::RateLimited(e) => write!(f, "Rate limited: {}", e),
+            #[cfg(feature = "client")]
+            Error::TokenNotFound(id) => write!(f, "Token not found: {}", id),
+            #[cfg(feature = "client")]
+            Error::InvalidArgument(e) => write!(f, "Invalid argument: {}", e),
             Error::Transport(e) => write!(f, "Transport error: {}", e)
         }
     }
@@ -19,7 +61,87 @@ impl Display for Error {
 
 #[cfg(feature = "client")]
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub enum CredentialsError {}
+pub enum CredentialsError {
+    ///
+    /// The server denied the authentication, e.g., because the secret is wrong or revoked.
+    /// The status is the code from `AuthResponse`, or 0 if the server responded with an error instead
+    Denied {
+        status: u32,
+        deny_message: String,
+    },
+    ///
+    /// The server rejected the refresh token, and there is no secret to authenticate again
+    RefreshRejected,
+    ///
+    /// The server sent, or the credentials provider gave, a JWT that cannot be used
+    MalformedJwt,
+    ///
+    /// The credentials have no secret to authenticate with
+    MissingSecret,
+    ///
+    /// The fixed JWT (see `Credentials::jwt_until`) is expired
+    JwtExpired,
+    ///
+    /// The fixed JWT (see `Credentials::jwt`) is rejected by the server
+    JwtRejected,
+    ///
+    /// The credentials provider failed to give a secret or a JWT
+    Provider(String),
+    ///
+    /// The server granted fewer scopes than required (see `Credentials::require_scopes`), with the list of missing scopes
+    ScopesNotGranted(Vec<String>),
+}
+
+#[cfg(feature = "client")]
+impl Display for CredentialsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialsError::Denied { status, deny_message } => write!(f, "Authentication denied with status {}: {}", status, deny_message),
+            CredentialsError::RefreshRejected => write!(f, "Refresh token is rejected, and there is no secret to authenticate again"),
+            CredentialsError::MalformedJwt => write!(f, "JWT cannot be used"),
+            CredentialsError::MissingSecret => write!(f, "No secret to authenticate with"),
+            CredentialsError::JwtExpired => write!(f, "JWT is expired"),
+            CredentialsError::JwtRejected => write!(f, "JWT is rejected by the server"),
+            CredentialsError::Provider(e) => write!(f, "Credentials provider failed: {}", e),
+            CredentialsError::ScopesNotGranted(scopes) => write!(f, "Scopes are not granted: {}", scopes.join(", ")),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl CredentialsError {
+    ///
+    /// Get the credentials error a call failed with, if it's failed because of the credentials
+    ///
+    /// @param status - status of the failed call
+    pub fn from_status(status: &tonic::Status) -> Option<&CredentialsError> {
+        match Error::from_status(status) {
+            Some(Error::Credentials(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl std::error::Error for CredentialsError {}
+
+#[cfg(feature = "tonic")]
+impl Error {
+    ///
+    /// Get the error of the crate a call failed with, e.g., `Error::RateLimited`, if the status is made from it
+    ///
+    /// @param status - status of the failed call
+    pub fn from_status(status: &tonic::Status) -> Option<&Error> {
+        std::error::Error::source(status).and_then(|e| e.downcast_ref::<Error>())
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<CredentialsError> for Error {
+    fn from(e: CredentialsError) -> Self {
+        Error::Credentials(e)
+    }
+}
 
 #[cfg(feature = "tonic")]
 impl From<tonic::transport::Error> for Error {
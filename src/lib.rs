@@ -3,12 +3,31 @@ extern crate prost;
 #[cfg(feature = "auth")]
 pub mod auth {
     #[cfg(feature = "client-auth")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-auth")]
     use crate::proto::auth::auth_client;
 
+    #[cfg(any(feature = "client-auth", feature = "server-auth"))]
+    use crate::policy::AuthRequirement;
+
+    ///
+    /// Methods of the Auth API that don't need authentication
+    #[cfg(any(feature = "client-auth", feature = "server-auth"))]
+    pub const AUTH_POLICY: &[(&str, AuthRequirement)] = &[
+        ("/emerald.Auth/Authenticate", AuthRequirement::Public),
+        ("/emerald.Auth/Refresh", AuthRequirement::Public),
+    ];
+
+    ///
+    /// Methods of the Auth API that are safe to retry
+    #[cfg(feature = "client-auth")]
+    pub const IDEMPOTENT_METHODS: &[&str] = &[
+        "/emerald.Auth/WhoAmI",
+        "/emerald.Auth/ListTokens",
+    ];
+
     #[cfg(feature = "client-auth")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) ->  auth_client::AuthClient<AuthService<tonic::transport::Channel>> {
+    pub fn connect(conn: &crate::conn::EmeraldConn) ->  auth_client::AuthClient<ApiChannel> {
         auth_client::AuthClient::new(conn.channel())
     }
 }
@@ -16,12 +35,12 @@ pub mod auth {
 #[cfg(feature = "blockchain")]
 pub mod blockchain {
     #[cfg(feature = "client-blockchain")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-blockchain")]
     use crate::proto::blockchain::blockchain_client;
 
     #[cfg(feature = "client-blockchain")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) ->  blockchain_client::BlockchainClient<AuthService<tonic::transport::Channel>> {
+    pub fn connect(conn: &crate::conn::EmeraldConn) ->  blockchain_client::BlockchainClient<ApiChannel> {
         blockchain_client::BlockchainClient::new(conn.channel())
     }
 }
@@ -29,33 +48,50 @@ pub mod blockchain {
 #[cfg(feature = "market")]
 pub mod market {
     #[cfg(feature = "client-market")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-market")]
     use crate::proto::market::market_client;
+    #[cfg(any(feature = "client-market", feature = "server-market"))]
+    use crate::policy::AuthRequirement;
+
+    ///
+    /// Methods of the Market API that don't need authentication
+    #[cfg(any(feature = "client-market", feature = "server-market"))]
+    pub const AUTH_POLICY: &[(&str, AuthRequirement)] = &[
+        ("/emerald.Market/GetRates", AuthRequirement::Optional),
+    ];
+
+    ///
+    /// Methods of the Market API that are safe to retry
     #[cfg(feature = "client-market")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) -> market_client::MarketClient<AuthService<tonic::transport::Channel>> {
+    pub const IDEMPOTENT_METHODS: &[&str] = &[
+        "/emerald.Market/GetRates",
+    ];
+
+    #[cfg(feature = "client-market")]
+    pub fn connect(conn: &crate::conn::EmeraldConn) -> market_client::MarketClient<ApiChannel> {
         market_client::MarketClient::new(conn.channel())
     }
 }
 #[cfg(feature = "monitoring")]
 pub mod monitoring {
     #[cfg(feature = "client-monitoring")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-monitoring")]
     use crate::proto::monitoring::monitoring_client;
     #[cfg(feature = "client-monitoring")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) -> monitoring_client::MonitoringClient<AuthService<tonic::transport::Channel>> {
+    pub fn connect(conn: &crate::conn::EmeraldConn) -> monitoring_client::MonitoringClient<ApiChannel> {
         monitoring_client::MonitoringClient::new(conn.channel())
     }
 }
 #[cfg(feature = "transaction")]
 pub mod transaction {
     #[cfg(feature = "client-transaction")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-transaction")]
     use crate::proto::transaction::transaction_client;
     #[cfg(feature = "client-transaction")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) -> transaction_client::TransactionClient<AuthService<tonic::transport::Channel>> {
+    pub fn connect(conn: &crate::conn::EmeraldConn) -> transaction_client::TransactionClient<ApiChannel> {
         transaction_client::TransactionClient::new(conn.channel())
     }
 }
@@ -63,11 +99,11 @@ pub mod transaction {
 #[cfg(feature = "address")]
 pub mod address {
     #[cfg(feature = "client-address")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-address")]
     use crate::proto::address::address_client;
     #[cfg(feature = "client-address")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) -> address_client::AddressClient<AuthService<tonic::transport::Channel>> {
+    pub fn connect(conn: &crate::conn::EmeraldConn) -> address_client::AddressClient<ApiChannel> {
         address_client::AddressClient::new(conn.channel())
     }
 }
@@ -75,11 +111,11 @@ pub mod address {
 #[cfg(feature = "token")]
 pub mod token {
     #[cfg(feature = "client-token")]
-    use crate::creds::AuthService;
+    use crate::conn::ApiChannel;
     #[cfg(feature = "client-token")]
     use crate::proto::token::token_client;
     #[cfg(feature = "client-token")]
-    pub fn connect(conn: &crate::conn::EmeraldConn) -> token_client::TokenClient<AuthService<tonic::transport::Channel>> {
+    pub fn connect(conn: &crate::conn::EmeraldConn) -> token_client::TokenClient<ApiChannel> {
         token_client::TokenClient::new(conn.channel())
     }
 }
@@ -89,30 +125,30 @@ pub mod sierra {
 
     #[cfg(feature = "client-sierra")]
     pub mod org {
-        use crate::creds::AuthService;
+        use crate::conn::ApiChannel;
         use crate::proto::sierra::org_client;
 
-        pub fn connect(conn: &crate::conn::EmeraldConn) -> org_client::OrgClient<AuthService<tonic::transport::Channel>> {
+        pub fn connect(conn: &crate::conn::EmeraldConn) -> org_client::OrgClient<ApiChannel> {
             org_client::OrgClient::new(conn.channel())
         }
     }
 
     #[cfg(feature = "client-sierra")]
     pub mod project {
-        use crate::creds::AuthService;
+        use crate::conn::ApiChannel;
         use crate::proto::sierra::project_client;
 
-        pub fn connect(conn: &crate::conn::EmeraldConn) -> project_client::ProjectClient<AuthService<tonic::transport::Channel>> {
+        pub fn connect(conn: &crate::conn::EmeraldConn) -> project_client::ProjectClient<ApiChannel> {
             project_client::ProjectClient::new(conn.channel())
         }
     }
 
     #[cfg(feature = "client-sierra")]
     pub mod stat {
-        use crate::creds::AuthService;
+        use crate::conn::ApiChannel;
         use crate::proto::sierra::stat_client;
 
-        pub fn connect(conn: &crate::conn::EmeraldConn) -> stat_client::StatClient<AuthService<tonic::transport::Channel>> {
+        pub fn connect(conn: &crate::conn::EmeraldConn) -> stat_client::StatClient<ApiChannel> {
             stat_client::StatClient::new(conn.channel())
         }
     }
@@ -202,5 +238,35 @@ pub mod errors;
 #[cfg(feature = "client")]
 pub mod conn;
 #[cfg(feature = "client")]
+pub mod builder;
+#[cfg(feature = "client")]
+pub mod balance;
+#[cfg(feature = "client")]
 pub mod creds;
+#[cfg(feature = "client")]
+pub mod provider;
+#[cfg(feature = "client")]
+pub mod store;
+#[cfg(feature = "client")]
+pub mod token_manager;
+#[cfg(feature = "client")]
+pub mod secret;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod policy;
+#[cfg(any(feature = "client", feature = "server-auth"))]
+pub mod claims;
+#[cfg(feature = "server-auth")]
+pub mod verify;
+#[cfg(feature = "server-auth-memory")]
+pub mod memory_auth;
+#[cfg(feature = "client")]
+pub mod retry;
+#[cfg(feature = "client")]
+pub mod ratelimit;
+#[cfg(feature = "client")]
+mod replay;
+#[cfg(feature = "client")]
+mod clock;
+#[cfg(any(feature = "client", feature = "server-auth-memory"))]
+mod private_file;
 pub mod common;
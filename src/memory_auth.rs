@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tonic::{Request, Response, Status};
+use crate::claims::JwtClaims;
+use crate::proto::auth::{
+    auth_server::Auth,
+    auth_request::AuthType,
+    AuthRequest, AuthResponse,
+    DeleteTokenRequest, DeleteTokenResponse,
+    IssueTokenRequest, IssuedTokenResponse,
+    ListTokensRequest, ListTokensResponse,
+    RefreshRequest,
+    TokenDetails,
+    WhoAmIRequest, WhoAmIResponse,
+};
+use crate::verify::{bearer_token, JwtVerifier, VerificationKey};
+
+///
+/// Default lifetime of an issued JWT
+pub const DEFAULT_ACCESS_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+///
+/// Default lifetime of a refresh token
+pub const DEFAULT_REFRESH_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+///
+/// Status in `AuthResponse` when the secret or the refresh token is not accepted
+pub const STATUS_DENIED: u32 = 1;
+
+///
+/// An API token (i.e., a secret to exchange for a JWT)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ApiToken {
+    token_id: String,
+    user_id: String,
+    organization_id: String,
+    project_id: String,
+    /// creation time in milliseconds
+    created_at: u64,
+    /// expiration time in milliseconds, or 0 if it never expires
+    expire_at: u64,
+    display_name: String,
+    scopes: Vec<String>,
+}
+
+///
+/// A refresh token issued with a JWT
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session {
+    /// the API token it's originally authenticated with
+    token_id: String,
+    user_id: String,
+    scopes: Vec<String>,
+    capabilities: Vec<String>,
+    /// expiration time in milliseconds
+    expires_at: u64,
+}
+
+///
+/// All issued tokens. The secrets are never kept as is, only their hashes are used as keys.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tokens {
+    api_tokens: HashMap<String, ApiToken>,
+    sessions: HashMap<String, Session>,
+    /// incremented on each change, to never replace the file with an older state
+    #[serde(skip)]
+    version: u64,
+}
+
+///
+/// State of the tokens to write to the file
+struct Snapshot {
+    version: u64,
+    content: Vec<u8>,
+}
+
+///
+/// A complete implementation of the Auth service that keeps everything in memory, and optionally in a file.
+/// It's supposed to be used for local environments and tests instead of the actual Emerald API.
+///
+/// The JWTs are signed with HMAC by default, and can be verified by `verifier()`.
+///
+/// Example:
+/// ```ignore
+/// let auth = MemoryAuthService::new(b"signing_secret")
+///     .with_token("secret_token", "user_001", vec![]);
+/// Server::builder()
+///     .add_service(AuthServer::new(auth))
+///     .serve(addr).await?;
+/// ```
+pub struct MemoryAuthService {
+    key: EncodingKey,
+    algorithm: Algorithm,
+    verifier: JwtVerifier,
+    access_lifetime: Duration,
+    refresh_lifetime: Duration,
+    issuer: Option<String>,
+    tokens: Mutex<Tokens>,
+    /// file to persist the tokens to
+    path: Option<PathBuf>,
+    /// version of the tokens last written to the file
+    written: Arc<Mutex<u64>>,
+}
+
+impl MemoryAuthService {
+    ///
+    /// Create the service that issues JWTs signed with HS256
+    ///
+    /// @param secret - secret to sign the JWTs
+    pub fn new(secret: &[u8]) -> Self {
+        Self::with_signing_key(EncodingKey::from_secret(secret), Algorithm::HS256, VerificationKey::hmac(secret))
+    }
+
+    ///
+    /// Create the service that issues JWTs signed with the specified key, e.g., an RSA or EC private key
+    ///
+    /// @param key - key to sign the JWTs
+    /// @param algorithm - signature algorithm, must be supported by the key
+    /// @param verification - the key to verify the signature, e.g., the public key
+    pub fn with_signing_key(key: EncodingKey, algorithm: Algorithm, verification: VerificationKey) -> Self {
+        MemoryAuthService {
+            key,
+            algorithm,
+            verifier: JwtVerifier::new(vec![verification]),
+            access_lifetime: DEFAULT_ACCESS_LIFETIME,
+            refresh_lifetime: DEFAULT_REFRESH_LIFETIME,
+            issuer: None,
+            tokens: Mutex::new(Tokens::default()),
+            path: None,
+            written: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    ///
+    /// Set how long the issued JWTs are valid. Default is `DEFAULT_ACCESS_LIFETIME`.
+    pub fn with_access_lifetime(self, lifetime: Duration) -> Self {
+        MemoryAuthService {
+            access_lifetime: lifetime,
+            ..self
+        }
+    }
+
+    ///
+    /// Set how long the refresh tokens are valid. Default is `DEFAULT_REFRESH_LIFETIME`.
+    pub fn with_refresh_lifetime(self, lifetime: Duration) -> Self {
+        MemoryAuthService {
+            refresh_lifetime: lifetime,
+            ..self
+        }
+    }
+
+    ///
+    /// Set the issuer (`iss`) of the JWTs
+    pub fn with_issuer<S: ToString>(self, issuer: S) -> Self {
+        MemoryAuthService {
+            verifier: self.verifier.clone().with_issuer(issuer.to_string()),
+            issuer: Some(issuer.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Keep the tokens in the file, so they are available after a restart. The existing tokens are loaded from it.
+    /// A failure to read or write the file is logged and otherwise ignored.
+    ///
+    /// @param path - path to the JSON file; created on first change
+    pub fn with_file<P: Into<PathBuf>>(self, path: P) -> Self {
+        let path = path.into();
+        let tokens = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                tracing::warn!("Invalid tokens file at {}: {}", path.display(), e);
+                Tokens::default()
+            }),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Cannot read tokens file at {}: {}", path.display(), e);
+                }
+                Tokens::default()
+            }
+        };
+        MemoryAuthService {
+            tokens: Mutex::new(tokens),
+            path: Some(path),
+            ..self
+        }
+    }
+
+    ///
+    /// Add an API token with a known secret, e.g., to configure a local environment
+    ///
+    /// @param secret - the secret to authenticate with
+    /// @param user_id - user the token belongs to
+    /// @param scopes - scopes the token can be used for; empty means any scope
+    pub fn with_token<S: ToString>(self, secret: &str, user_id: S, scopes: Vec<String>) -> Self {
+        // the id is not a secret, so it's fine to fall back to a sequential one
+        let token_id = random_hex(8).unwrap_or_else(|_| {
+            format!("{:016x}", self.tokens.lock().unwrap().api_tokens.len() + 1)
+        });
+        let snapshot = self.add_token(secret, ApiToken {
+            token_id,
+            user_id: user_id.to_string(),
+            organization_id: String::new(),
+            project_id: String::new(),
+            created_at: now_millis(),
+            expire_at: 0,
+            display_name: String::new(),
+            scopes,
+        });
+        if let (Some(path), Some(snapshot)) = (&self.path, snapshot) {
+            write(path, &self.written, snapshot);
+        }
+        self
+    }
+
+    ///
+    /// Verifier for the JWTs issued by this service, e.g., to use with `crate::verify::JwtVerifyLayer` for other services
+    pub fn verifier(&self) -> JwtVerifier {
+        self.verifier.clone()
+    }
+
+    fn add_token(&self, secret: &str, token: ApiToken) -> Option<Snapshot> {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.api_tokens.insert(hash(secret), token);
+        self.snapshot(&mut tokens)
+    }
+
+    ///
+    /// Mark the tokens as changed, and get the state to write to the file, if it's used
+    fn snapshot(&self, tokens: &mut Tokens) -> Option<Snapshot> {
+        self.path.as_ref()?;
+        tokens.version += 1;
+        match serde_json::to_vec(tokens) {
+            Ok(content) => Some(Snapshot { version: tokens.version, content }),
+            Err(e) => {
+                tracing::warn!("Cannot serialize tokens: {}", e);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Write the tokens to the file. Must be called after the lock on the tokens is released.
+    async fn save(&self, snapshot: Option<Snapshot>) {
+        let (Some(path), Some(snapshot)) = (self.path.clone(), snapshot) else {
+            return;
+        };
+        let written = self.written.clone();
+        let _ = tokio::task::spawn_blocking(move || write(&path, &written, snapshot)).await;
+    }
+
+    ///
+    /// Sign a new JWT and create a refresh token for it
+    fn start_session(&self, tokens: &mut Tokens, session: Session) -> Result<(AuthResponse, Option<Snapshot>), Status> {
+        let now = Utc::now();
+        let expires_at = now + self.access_lifetime;
+        let mut claims = serde_json::json!({
+            "sub": session.user_id,
+            "iat": now.timestamp(),
+            "exp": expires_at.timestamp(),
+            "jti": random_hex(8)?,
+        });
+        if !session.scopes.is_empty() {
+            claims["scope"] = session.scopes.join(" ").into();
+        }
+        if !session.capabilities.is_empty() {
+            claims["capabilities"] = session.capabilities.clone().into();
+        }
+        if let Some(issuer) = &self.issuer {
+            claims["iss"] = issuer.clone().into();
+        }
+        let jwt = jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.key)
+            .map_err(|e| Status::internal(format!("Cannot sign JWT: {}", e)))?;
+
+        let refresh_token = random_hex(32)?;
+        tokens.sessions.retain(|_, session| session.expires_at > now_millis());
+        tokens.sessions.insert(hash(&refresh_token), Session {
+            expires_at: now_millis() + self.refresh_lifetime.as_millis() as u64,
+            ..session
+        });
+        let snapshot = self.snapshot(tokens);
+
+        let response = AuthResponse {
+            status: 0,
+            access_token: jwt,
+            refresh_token,
+            expires_at: expires_at.timestamp_millis() as u64,
+            ..Default::default()
+        };
+        Ok((response, snapshot))
+    }
+
+    ///
+    /// Get the claims of the JWT the request is authenticated with
+    fn caller<T>(&self, request: &Request<T>) -> Result<JwtClaims, Status> {
+        let jwt = bearer_token(request.metadata().as_ref())?;
+        let claims = self.verifier.verify(jwt)?;
+        if claims.subject.is_none() {
+            return Err(Status::unauthenticated("JWT has no subject"));
+        }
+        Ok(claims)
+    }
+}
+
+///
+/// Check that the caller asks for its own tokens, and get the user id
+fn own_user_id(claims: &JwtClaims, user_id: &str) -> Result<String, Status> {
+    let caller = claims.subject.clone().unwrap_or_default();
+    if !user_id.is_empty() && user_id != caller {
+        return Err(Status::permission_denied("Tokens of another user"));
+    }
+    Ok(caller)
+}
+
+fn denied<S: ToString>(message: S) -> Response<AuthResponse> {
+    Response::new(AuthResponse {
+        status: STATUS_DENIED,
+        deny_message: message.to_string(),
+        ..Default::default()
+    })
+}
+
+fn hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn random_hex(len: usize) -> Result<String, Status> {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).map_err(|e| Status::internal(format!("System random is not available: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+///
+/// Write the tokens to the file, unless a newer state is already written.
+/// It's a blocking call.
+fn write(path: &Path, written: &Mutex<u64>, snapshot: Snapshot) {
+    let mut written = written.lock().unwrap();
+    if *written >= snapshot.version {
+        return;
+    }
+    match crate::private_file::write(path, &snapshot.content) {
+        Ok(_) => *written = snapshot.version,
+        Err(e) => tracing::warn!("Cannot write tokens file at {}: {}", path.display(), e),
+    }
+}
+
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+#[tonic::async_trait]
+impl Auth for MemoryAuthService {
+    async fn authenticate(&self, request: Request<AuthRequest>) -> Result<Response<AuthResponse>, Status> {
+        let request = request.into_inner();
+        let secret = match request.auth_type {
+            Some(AuthType::AuthSecret(secret)) => secret,
+            Some(_) => return Err(Status::unimplemented("Only a secret is supported")),
+            None => return Err(Status::invalid_argument("Missing auth type")),
+        };
+        let (response, snapshot) = {
+            let mut tokens = self.tokens.lock().unwrap();
+            let Some(token) = tokens.api_tokens.get(&hash(&secret)).cloned() else {
+                return Ok(denied("Invalid secret"));
+            };
+            if token.expire_at != 0 && token.expire_at <= now_millis() {
+                return Ok(denied("Token expired"));
+            }
+            // a token without scopes can be used for anything, otherwise only the allowed scopes are granted
+            let scopes = if token.scopes.is_empty() {
+                request.scopes
+            } else if request.scopes.is_empty() {
+                token.scopes.clone()
+            } else {
+                request.scopes.into_iter().filter(|scope| token.scopes.contains(scope)).collect()
+            };
+            let session = Session {
+                token_id: token.token_id,
+                user_id: token.user_id,
+                scopes,
+                capabilities: request.capabilities,
+                expires_at: 0,
+            };
+            self.start_session(&mut tokens, session)?
+        };
+        self.save(snapshot).await;
+        Ok(Response::new(response))
+    }
+
+    async fn refresh(&self, request: Request<RefreshRequest>) -> Result<Response<AuthResponse>, Status> {
+        let request = request.into_inner();
+        let (response, snapshot) = {
+            let mut tokens = self.tokens.lock().unwrap();
+            // a refresh token can be used only once
+            let Some(session) = tokens.sessions.remove(&hash(&request.refresh_token)) else {
+                return Ok(denied("Invalid refresh token"));
+            };
+            if session.expires_at <= now_millis() {
+                (denied("Refresh token expired"), self.snapshot(&mut tokens))
+            } else if !tokens.api_tokens.values().any(|token| token.token_id == session.token_id) {
+                (denied("Token deleted"), self.snapshot(&mut tokens))
+            } else {
+                let (response, snapshot) = self.start_session(&mut tokens, session)?;
+                (Response::new(response), snapshot)
+            }
+        };
+        self.save(snapshot).await;
+        Ok(response)
+    }
+
+    async fn issue_token(&self, request: Request<IssueTokenRequest>) -> Result<Response<IssuedTokenResponse>, Status> {
+        let claims = self.caller(&request)?;
+        let request = request.into_inner();
+        let user_id = own_user_id(&claims, &request.user_id)?;
+        let secret = random_hex(32)?;
+        let token_id = random_hex(8)?;
+        let snapshot = self.add_token(&secret, ApiToken {
+            token_id: token_id.clone(),
+            user_id,
+            organization_id: request.organization_id,
+            project_id: request.project_id,
+            created_at: now_millis(),
+            expire_at: request.expire_at,
+            display_name: request.display_name,
+            scopes: request.scopes,
+        });
+        self.save(snapshot).await;
+        Ok(Response::new(IssuedTokenResponse {
+            access_token: secret,
+            token_id,
+            ..Default::default()
+        }))
+    }
+
+    async fn who_am_i(&self, request: Request<WhoAmIRequest>) -> Result<Response<WhoAmIResponse>, Status> {
+        let response = match self.caller(&request) {
+            Ok(claims) => WhoAmIResponse {
+                is_authenticated: true,
+                user_id: claims.subject.unwrap_or_default(),
+                ..Default::default()
+            },
+            Err(_) => WhoAmIResponse {
+                is_authenticated: false,
+                ..Default::default()
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn list_tokens(&self, request: Request<ListTokensRequest>) -> Result<Response<ListTokensResponse>, Status> {
+        let claims = self.caller(&request)?;
+        let request = request.into_inner();
+        let user_id = own_user_id(&claims, &request.user_id)?;
+        let tokens = self.tokens.lock().unwrap();
+        let mut found: Vec<TokenDetails> = tokens.api_tokens.values()
+            .filter(|token| token.user_id == user_id)
+            .filter(|token| request.organization_id.is_empty() || token.organization_id == request.organization_id)
+            .map(|token| TokenDetails {
+                token_id: token.token_id.clone(),
+                user_id: token.user_id.clone(),
+                created_at: token.created_at,
+                expire_at: token.expire_at,
+                display_name: token.display_name.clone(),
+                scopes: token.scopes.clone(),
+                ..Default::default()
+            })
+            .collect();
+        found.sort_by_key(|token| token.created_at);
+        Ok(Response::new(ListTokensResponse {
+            tokens: found,
+            ..Default::default()
+        }))
+    }
+
+    async fn delete_token(&self, request: Request<DeleteTokenRequest>) -> Result<Response<DeleteTokenResponse>, Status> {
+        let claims = self.caller(&request)?;
+        let request = request.into_inner();
+        let user_id = own_user_id(&claims, &request.user_id)?;
+        let (deleted, snapshot) = {
+            let mut tokens = self.tokens.lock().unwrap();
+            let before = tokens.api_tokens.len();
+            tokens.api_tokens.retain(|_, token| !(token.token_id == request.token_id && token.user_id == user_id));
+            let deleted = tokens.api_tokens.len() != before;
+            if deleted {
+                tokens.sessions.retain(|_, session| session.token_id != request.token_id);
+                (true, self.snapshot(&mut tokens))
+            } else {
+                (false, None)
+            }
+        };
+        self.save(snapshot).await;
+        Ok(Response::new(DeleteTokenResponse {
+            deleted,
+            ..Default::default()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Request;
+    use crate::memory_auth::{MemoryAuthService, STATUS_DENIED};
+    use crate::proto::auth::{auth_server::Auth, auth_request::AuthType, AuthRequest, RefreshRequest};
+
+    fn auth_request(secret: &str) -> Request<AuthRequest> {
+        Request::new(AuthRequest {
+            auth_type: Some(AuthType::AuthSecret(secret.to_string())),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_keeps_tokens_in_file() {
+        let path = std::env::temp_dir().join(format!("emerald-api-memory-auth-{}.json", std::process::id()));
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_file(&path)
+            .with_token("secret_token", "user_001", vec![]);
+        let response = service.authenticate(auth_request("secret_token")).await.unwrap().into_inner();
+        assert_eq!(response.status, 0);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("secret_token"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert!(!content.contains(&response.refresh_token));
+
+        // restarted
+        let service = MemoryAuthService::new(b"signing_secret").with_file(&path);
+        let refreshed = service.refresh(Request::new(RefreshRequest { refresh_token: response.refresh_token.clone() })).await.unwrap().into_inner();
+        assert_eq!(refreshed.status, 0);
+        assert_ne!(refreshed.access_token, response.access_token);
+
+        // a refresh token can be used only once
+        let again = service.refresh(Request::new(RefreshRequest { refresh_token: response.refresh_token })).await.unwrap().into_inner();
+        assert_eq!(again.status, STATUS_DENIED);
+
+        let denied = service.authenticate(auth_request("other_secret")).await.unwrap().into_inner();
+        assert_eq!(denied.status, STATUS_DENIED);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
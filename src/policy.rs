@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+///
+/// How a gRPC method needs to be authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthRequirement {
+    ///
+    /// The call needs a JWT, and the client authenticates first if it doesn't have one
+    Required,
+    ///
+    /// The call works without a JWT, but may give more with it. The JWT is attached only if the client already has a valid one.
+    Optional,
+    ///
+    /// The call never needs a JWT, and it's never attached
+    Public,
+}
+
+///
+/// Auth requirements for the gRPC methods called through a connection (see `EmeraldConn::with_auth_policy`), or served behind `JwtVerifyLayer`.
+///
+/// A method is specified by its full path, e.g., `/emerald.Market/GetRates`, or all methods of a service with `/emerald.Market/*`.
+/// The methods not mentioned in the policy use the default requirement, which is `AuthRequirement::Required`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthPolicy {
+    methods: HashMap<String, AuthRequirement>,
+    default: AuthRequirement,
+}
+
+impl Default for AuthPolicy {
+    ///
+    /// The standard policy for all the APIs enabled in the crate.
+    /// Only the Auth and Market APIs have methods callable without a JWT; all methods of the other APIs (Blockchain, Monitoring, etc.) need it.
+    fn default() -> Self {
+        let policy = AuthPolicy::empty();
+        #[cfg(any(feature = "client-auth", feature = "server-auth"))]
+        let policy = policy.with_all(crate::auth::AUTH_POLICY);
+        #[cfg(any(feature = "client-market", feature = "server-market"))]
+        let policy = policy.with_all(crate::market::AUTH_POLICY);
+        policy
+    }
+}
+
+impl AuthPolicy {
+    ///
+    /// A policy that requires authentication for all methods
+    pub fn empty() -> Self {
+        AuthPolicy {
+            methods: HashMap::new(),
+            default: AuthRequirement::Required,
+        }
+    }
+
+    ///
+    /// Set the requirement for the method, or for all methods of a service
+    ///
+    /// @param method - full path of the method, e.g., `/emerald.Market/GetRates`, or `/emerald.Market/*` for the whole service
+    /// @param requirement - requirement for the method
+    pub fn with<S: ToString>(mut self, method: S, requirement: AuthRequirement) -> Self {
+        self.methods.insert(method.to_string(), requirement);
+        self
+    }
+
+    ///
+    /// Set the requirements for multiple methods, e.g., the predefined policy of an API like `market::AUTH_POLICY`
+    pub fn with_all(self, methods: &[(&str, AuthRequirement)]) -> Self {
+        methods.iter().fold(self, |policy, (method, requirement)| policy.with(method, *requirement))
+    }
+
+    ///
+    /// Set the requirement for the methods not mentioned in the policy
+    pub fn with_default(self, requirement: AuthRequirement) -> Self {
+        AuthPolicy {
+            default: requirement,
+            ..self
+        }
+    }
+
+    ///
+    /// Find the requirement for the method
+    ///
+    /// @param path - path of the gRPC call, e.g., `/emerald.Market/GetRates`
+    pub fn requirement(&self, path: &str) -> AuthRequirement {
+        if let Some(requirement) = self.methods.get(path) {
+            return *requirement;
+        }
+        path.rsplit_once('/')
+            .and_then(|(service, _)| self.methods.get(&format!("{}/*", service)))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::{AuthPolicy, AuthRequirement};
+
+    #[test]
+    fn test_find_requirement() {
+        let policy = AuthPolicy::empty()
+            .with("/emerald.Market/*", AuthRequirement::Public)
+            .with("/emerald.Market/Subscribe", AuthRequirement::Optional);
+        assert_eq!(policy.requirement("/emerald.Market/GetRates"), AuthRequirement::Public);
+        assert_eq!(policy.requirement("/emerald.Market/Subscribe"), AuthRequirement::Optional);
+        assert_eq!(policy.requirement("/emerald.Blockchain/NativeCall"), AuthRequirement::Required);
+
+        let policy = policy.with_default(AuthRequirement::Optional);
+        assert_eq!(policy.requirement("/emerald.Blockchain/NativeCall"), AuthRequirement::Optional);
+    }
+
+    #[test]
+    #[cfg(any(feature = "client-market", feature = "server-market"))]
+    fn test_default_market_policy() {
+        let policy = AuthPolicy::default();
+        assert_eq!(policy.requirement("/emerald.Market/GetRates"), AuthRequirement::Optional);
+        assert_eq!(policy.requirement("/emerald.Blockchain/NativeCall"), AuthRequirement::Required);
+    }
+}
@@ -0,0 +1,42 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+///
+/// Replace the content of the file readable only by the current user, so a concurrent reader never sees it partially written.
+/// It's written to a temp file first, which is then renamed to the target. The temp name is unique for the process and the call,
+/// so other processes never write into the same temp file.
+///
+/// NOTE: it's a blocking call, so in an async context it must be made with `tokio::task::spawn_blocking`
+///
+/// @param path - the file to write
+/// @param content - the new content
+pub(crate) fn write(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut suffix = [0u8; 8];
+    getrandom::fill(&mut suffix).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let suffix = suffix.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(format!(".{}.{}.tmp", std::process::id(), suffix));
+    let tmp = PathBuf::from(tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options.open(&tmp).and_then(|mut file| {
+        // the mode above is applied only through the umask, so set it explicitly
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
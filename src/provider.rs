@@ -0,0 +1,276 @@
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use crate::errors::CredentialsError;
+use crate::secret::Secret;
+
+///
+/// Default time to reuse the output of a command before running it again
+pub const DEFAULT_COMMAND_CACHE: Duration = Duration::from_secs(60);
+
+///
+/// Credentials given by a provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProvidedCredentials {
+    ///
+    /// A secret (ex. an API token) to exchange for a JWT
+    Secret(Secret),
+    ///
+    /// A JWT issued elsewhere, to use as is
+    Jwt(Secret),
+}
+
+///
+/// What kind of credentials a provider gives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvidedKind {
+    Secret,
+    Jwt,
+}
+
+impl ProvidedKind {
+    fn wrap(&self, value: Secret) -> ProvidedCredentials {
+        match self {
+            ProvidedKind::Secret => ProvidedCredentials::Secret(value),
+            ProvidedKind::Jwt => ProvidedCredentials::Jwt(value),
+        }
+    }
+}
+
+///
+/// A source of credentials that are not known in advance or may change over time.
+/// The client asks it each time it needs a secret to authenticate, and for each call if the provider gives a JWT.
+#[tonic::async_trait]
+pub trait CredentialsProvider: Send + Sync + Debug {
+    async fn credentials(&self) -> Result<ProvidedCredentials, CredentialsError>;
+}
+
+///
+/// Provides a fixed value
+#[derive(Debug, Clone)]
+pub struct LiteralProvider {
+    value: ProvidedCredentials,
+}
+
+impl LiteralProvider {
+    pub fn new(value: ProvidedCredentials) -> Self {
+        LiteralProvider {
+            value
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl CredentialsProvider for LiteralProvider {
+    async fn credentials(&self) -> Result<ProvidedCredentials, CredentialsError> {
+        Ok(self.value.clone())
+    }
+}
+
+///
+/// Reads the value from an environment variable each time it's requested
+#[derive(Debug, Clone)]
+pub struct EnvProvider {
+    name: String,
+    kind: ProvidedKind,
+}
+
+impl EnvProvider {
+    ///
+    /// Provide a secret from the environment variable
+    ///
+    /// @param name - name of the variable, e.g., `EMERALD_API_TOKEN`
+    pub fn new<S: ToString>(name: S) -> Self {
+        EnvProvider {
+            name: name.to_string(),
+            kind: ProvidedKind::Secret,
+        }
+    }
+
+    ///
+    /// Provide the value as a JWT instead of a secret
+    pub fn as_jwt(self) -> Self {
+        EnvProvider {
+            kind: ProvidedKind::Jwt,
+            ..self
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl CredentialsProvider for EnvProvider {
+    async fn credentials(&self) -> Result<ProvidedCredentials, CredentialsError> {
+        let value = std::env::var(&self.name)
+            .map(Secret::new)
+            .map_err(|e| CredentialsError::Provider(format!("Env variable {}: {}", self.name, e)))?;
+        Ok(self.kind.wrap(Secret::new(value.expose().trim())))
+    }
+}
+
+///
+/// Reads the value from a file. The file is read again only when its modification time changes.
+#[derive(Debug)]
+pub struct FileProvider {
+    path: PathBuf,
+    kind: ProvidedKind,
+    /// the last read value with the modification time of the file at that moment
+    current: Mutex<Option<(SystemTime, Secret)>>,
+}
+
+impl FileProvider {
+    ///
+    /// Provide a secret from the file
+    ///
+    /// @param path - path to the file with the secret
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileProvider {
+            path: path.into(),
+            kind: ProvidedKind::Secret,
+            current: Mutex::new(None),
+        }
+    }
+
+    ///
+    /// Provide the value as a JWT instead of a secret
+    pub fn as_jwt(self) -> Self {
+        FileProvider {
+            kind: ProvidedKind::Jwt,
+            ..self
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl CredentialsProvider for FileProvider {
+    async fn credentials(&self) -> Result<ProvidedCredentials, CredentialsError> {
+        let modified = tokio::fs::metadata(&self.path).await
+            .and_then(|meta| meta.modified())
+            .map_err(|e| CredentialsError::Provider(format!("File {}: {}", self.path.display(), e)))?;
+        if let Some((read_at, value)) = self.current.lock().unwrap().as_ref() {
+            if *read_at == modified {
+                return Ok(self.kind.wrap(value.clone()));
+            }
+        }
+        tracing::trace!("Reading credentials from {}", self.path.display());
+        let value = tokio::fs::read_to_string(&self.path).await
+            .map(Secret::new)
+            .map_err(|e| CredentialsError::Provider(format!("File {}: {}", self.path.display(), e)))?;
+        let value = Secret::new(value.expose().trim());
+        *self.current.lock().unwrap() = Some((modified, value.clone()));
+        Ok(self.kind.wrap(value))
+    }
+}
+
+///
+/// Runs a command and uses its output. The output is reused for some time (`DEFAULT_COMMAND_CACHE` by default) before running it again.
+#[derive(Debug)]
+pub struct CommandProvider {
+    program: String,
+    args: Vec<String>,
+    kind: ProvidedKind,
+    cache: Duration,
+    /// the last output with the moment it was received
+    current: Mutex<Option<(Instant, Secret)>>,
+}
+
+impl CommandProvider {
+    ///
+    /// Provide a secret from the output of the command
+    ///
+    /// @param program - program to run
+    /// @param args - arguments for the program
+    pub fn new<S: ToString>(program: S, args: Vec<String>) -> Self {
+        CommandProvider {
+            program: program.to_string(),
+            args,
+            kind: ProvidedKind::Secret,
+            cache: DEFAULT_COMMAND_CACHE,
+            current: Mutex::new(None),
+        }
+    }
+
+    ///
+    /// Provide the value as a JWT instead of a secret
+    pub fn as_jwt(self) -> Self {
+        CommandProvider {
+            kind: ProvidedKind::Jwt,
+            ..self
+        }
+    }
+
+    ///
+    /// Set how long to reuse the output before running the command again. With zero duration it runs the command each time.
+    pub fn with_cache(self, cache: Duration) -> Self {
+        CommandProvider {
+            cache,
+            ..self
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl CredentialsProvider for CommandProvider {
+    async fn credentials(&self) -> Result<ProvidedCredentials, CredentialsError> {
+        if let Some((received_at, value)) = self.current.lock().unwrap().as_ref() {
+            if received_at.elapsed() < self.cache {
+                return Ok(self.kind.wrap(value.clone()));
+            }
+        }
+        tracing::trace!("Running {} to get credentials", self.program);
+        let output = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .output().await
+            .map_err(|e| CredentialsError::Provider(format!("Command {}: {}", self.program, e)))?;
+        if !output.status.success() {
+            return Err(CredentialsError::Provider(format!("Command {} exited with {}", self.program, output.status)));
+        }
+        let value = String::from_utf8(output.stdout)
+            .map(Secret::new)
+            .map_err(|_| CredentialsError::Provider(format!("Command {} returned non UTF-8 output", self.program)))?;
+        let value = Secret::new(value.expose().trim());
+        *self.current.lock().unwrap() = Some((Instant::now(), value.clone()));
+        Ok(self.kind.wrap(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::errors::CredentialsError;
+    use crate::provider::{CommandProvider, CredentialsProvider, FileProvider, ProvidedCredentials};
+
+    #[tokio::test]
+    async fn test_reads_file_again_when_modified() {
+        let path = std::env::temp_dir().join(format!("emerald-api-provider-{}", std::process::id()));
+        std::fs::write(&path, "secret_001\n").unwrap();
+        let provider = FileProvider::new(&path);
+
+        assert_eq!(provider.credentials().await.unwrap(), ProvidedCredentials::Secret("secret_001".into()));
+
+        let file = std::fs::OpenOptions::new().write(true).truncate(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut &file, b"secret_002").unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(1)).unwrap();
+
+        assert_eq!(provider.credentials().await.unwrap(), ProvidedCredentials::Secret("secret_002".into()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fails_on_missing_file() {
+        let provider = FileProvider::new("/nonexistent/emerald-api-secret");
+        assert!(matches!(provider.credentials().await, Err(CredentialsError::Provider(_))));
+    }
+
+    #[tokio::test]
+    async fn test_reads_command_output() {
+        let provider = CommandProvider::new("echo", vec!["jwt_001".to_string()]).as_jwt();
+        assert_eq!(provider.credentials().await.unwrap(), ProvidedCredentials::Jwt("jwt_001".into()));
+    }
+
+    #[tokio::test]
+    async fn test_fails_on_command_error() {
+        let provider = CommandProvider::new("false", vec![]);
+        assert!(matches!(provider.credentials().await, Err(CredentialsError::Provider(_))));
+    }
+}
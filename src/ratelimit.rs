@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures::future::BoxFuture;
+use tonic::{body::Body, codegen::http, Code};
+use http::Response;
+use tower::{Layer, Service};
+use crate::errors::Error;
+use crate::retry::status_code;
+
+///
+/// Default limit for how long a call can wait for the rate limit
+pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(5);
+
+///
+/// Default limit for the number of calls waiting for the rate limit
+pub const DEFAULT_MAX_QUEUE: usize = 100;
+
+///
+/// Scope name of the global limit in `RateBudget`
+pub const GLOBAL_SCOPE: &str = "*";
+
+///
+/// The adapted rate never goes below this part of the configured rate
+const MIN_RATE_FRACTION: f64 = 0.1;
+
+///
+/// Time for the adapted rate to grow back from zero to the configured rate
+const RECOVERY_PERIOD: Duration = Duration::from_secs(30);
+
+///
+/// RESOURCE_EXHAUSTED responses received within this time after the rate is decreased are considered as a part of the same burst, and don't decrease it again
+const ADAPT_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// A token bucket: allows `requests` calls per `period` on average, and up to `burst` calls at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// calls per second
+    rate: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+    ///
+    /// Limit the calls to `requests` per `period`, which can be made all at once
+    ///
+    /// @param requests - number of calls
+    /// @param period - period of time, e.g., one second
+    pub fn new(requests: u32, period: Duration) -> Self {
+        let requests = requests.max(1) as f64;
+        RateLimit {
+            rate: requests / period.as_secs_f64().max(f64::EPSILON),
+            burst: requests,
+        }
+    }
+
+    ///
+    /// Limit the calls to `requests` per second
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    ///
+    /// Set how many calls can be made at once, after a period without calls. By default, it's the number of calls per period.
+    pub fn with_burst(self, burst: u32) -> Self {
+        RateLimit {
+            burst: burst.max(1) as f64,
+            ..self
+        }
+    }
+}
+
+///
+/// Client-side limits for the calls made through a connection (see `EmeraldConn::with_rate_limit`), to stay within the API quotas.
+///
+/// There is a global limit for all calls, and the limits for individual methods or services. A call takes a token from the global bucket and from the bucket of its method,
+/// which is the bucket of the exact method if it's configured, or of its service otherwise.
+/// If there is no token, the call waits for it in a queue. A call is rejected immediately with `Error::RateLimited` if the queue is full or if it would wait longer than the max wait.
+///
+/// When the server responds with RESOURCE_EXHAUSTED, the rates of the buckets used by the call are halved, and then grow back to the configured rate over time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitPolicy {
+    global: Option<RateLimit>,
+    methods: HashMap<String, RateLimit>,
+    max_wait: Duration,
+    max_queue: usize,
+    adaptive: bool,
+}
+
+impl Default for RateLimitPolicy {
+    ///
+    /// A policy without any limit
+    fn default() -> Self {
+        RateLimitPolicy {
+            global: None,
+            methods: HashMap::new(),
+            max_wait: DEFAULT_MAX_WAIT,
+            max_queue: DEFAULT_MAX_QUEUE,
+            adaptive: true,
+        }
+    }
+}
+
+impl RateLimitPolicy {
+    ///
+    /// Set the limit for all calls
+    pub fn with_global(self, limit: RateLimit) -> Self {
+        RateLimitPolicy {
+            global: Some(limit),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the limit for the method, or for all methods of a service together
+    ///
+    /// @param method - full path of the method, e.g., `/emerald.Market/GetRates`, or `/emerald.Market/*` for the whole service
+    /// @param limit - limit for the method
+    pub fn with_method<S: ToString>(mut self, method: S, limit: RateLimit) -> Self {
+        self.methods.insert(method.to_string(), limit);
+        self
+    }
+
+    ///
+    /// Set how long a call can wait for the rate limit. A call which would wait longer is rejected immediately. Default is `DEFAULT_MAX_WAIT`.
+    pub fn with_max_wait(self, max_wait: Duration) -> Self {
+        RateLimitPolicy {
+            max_wait,
+            ..self
+        }
+    }
+
+    ///
+    /// Set how many calls can wait for the rate limit at the same time. Other calls are rejected immediately. Default is `DEFAULT_MAX_QUEUE`.
+    pub fn with_max_queue(self, max_queue: usize) -> Self {
+        RateLimitPolicy {
+            max_queue,
+            ..self
+        }
+    }
+
+    ///
+    /// Enable or disable the adaptation of the rates to the RESOURCE_EXHAUSTED responses. Enabled by default.
+    pub fn with_adaptive(self, adaptive: bool) -> Self {
+        RateLimitPolicy {
+            adaptive,
+            ..self
+        }
+    }
+}
+
+///
+/// The current state of a rate limit, for monitoring (see `EmeraldConn::rate_budget`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateBudget {
+    ///
+    /// The method or service the limit is for (as specified in `RateLimitPolicy::with_method`), or `GLOBAL_SCOPE` for the global limit
+    pub scope: String,
+    ///
+    /// Configured rate, in calls per second
+    pub limit: f64,
+    ///
+    /// Current rate, in calls per second, which is lower than the configured one after RESOURCE_EXHAUSTED responses
+    pub rate: f64,
+    ///
+    /// Calls that can be made right now without waiting. Negative if the queue already took the upcoming tokens.
+    pub available: f64,
+    ///
+    /// Calls waiting in the queue
+    pub queued: usize,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    rate: f64,
+    updated: Instant,
+    decreased: Option<Instant>,
+    queued: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    scope: String,
+    limit: RateLimit,
+    state: Mutex<BucketState>,
+}
+
+impl Bucket {
+    fn new(scope: String, limit: RateLimit, now: Instant) -> Self {
+        Bucket {
+            scope,
+            limit,
+            state: Mutex::new(BucketState {
+                tokens: limit.burst,
+                rate: limit.rate,
+                updated: now,
+                decreased: None,
+                queued: 0,
+            }),
+        }
+    }
+
+    ///
+    /// Add the tokens for the time passed since the last update, and recover the rate if it was decreased
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * state.rate).min(self.capacity(state.rate));
+        state.rate = (state.rate + self.limit.rate * elapsed / RECOVERY_PERIOD.as_secs_f64()).min(self.limit.rate);
+        state.updated = now;
+    }
+
+    ///
+    /// Max tokens, which is proportionally lower when the rate is decreased
+    fn capacity(&self, rate: f64) -> f64 {
+        (self.limit.burst * rate / self.limit.rate).max(1.0)
+    }
+
+    ///
+    /// Take a token, or reserve the next one if there is no token right now. Returns how long to wait for the reserved token.
+    fn reserve(&self, now: Instant, max_wait: Duration, max_queue: usize) -> Result<Duration, Error> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            return Ok(Duration::ZERO)
+        }
+        if state.queued >= max_queue {
+            return Err(Error::RateLimited(format!("Too many calls waiting for {}", self.scope)))
+        }
+        let wait = Duration::from_secs_f64((1.0 - state.tokens) / state.rate);
+        if wait > max_wait {
+            return Err(Error::RateLimited(format!("Call to {} would wait {:?}", self.scope, wait)))
+        }
+        state.tokens -= 1.0;
+        state.queued += 1;
+        Ok(wait)
+    }
+
+    ///
+    /// Give back the token taken by `reserve`
+    fn refund(&self, waiting: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens += 1.0;
+        if waiting {
+            state.queued -= 1;
+        }
+    }
+
+    fn dequeue(&self) {
+        self.state.lock().unwrap().queued -= 1;
+    }
+
+    ///
+    /// Slow down after the server responded with RESOURCE_EXHAUSTED
+    fn decrease(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        if state.decreased.is_some_and(|decreased| now.saturating_duration_since(decreased) < ADAPT_INTERVAL) {
+            return
+        }
+        state.rate = (state.rate / 2.0).max(self.limit.rate * MIN_RATE_FRACTION);
+        // the server has no capacity right now, so don't make a burst of calls
+        state.tokens = state.tokens.min(0.0);
+        state.decreased = Some(now);
+        tracing::debug!("Rate limit of {} decreased to {:.2}/s", self.scope, state.rate);
+    }
+
+    fn budget(&self, now: Instant) -> RateBudget {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        RateBudget {
+            scope: self.scope.clone(),
+            limit: self.limit.rate,
+            rate: state.rate,
+            available: state.tokens,
+            queued: state.queued,
+        }
+    }
+}
+
+///
+/// Calls reserved a token and waiting for it. Leaves the queues when the call is done waiting or cancelled.
+struct Waiting<'a>(Vec<&'a Bucket>);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|bucket| bucket.dequeue());
+    }
+}
+
+///
+/// Rate limits of a connection, shared by all its clones
+pub(crate) struct RateLimitState {
+    global: Option<Bucket>,
+    methods: HashMap<String, Bucket>,
+    max_wait: Duration,
+    max_queue: usize,
+    adaptive: bool,
+}
+
+impl RateLimitState {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        let now = Instant::now();
+        RateLimitState {
+            global: policy.global.map(|limit| Bucket::new(GLOBAL_SCOPE.to_string(), limit, now)),
+            methods: policy.methods.into_iter()
+                .map(|(method, limit)| (method.clone(), Bucket::new(method, limit, now)))
+                .collect(),
+            max_wait: policy.max_wait,
+            max_queue: policy.max_queue,
+            adaptive: policy.adaptive,
+        }
+    }
+
+    ///
+    /// Find the buckets the call takes tokens from
+    ///
+    /// @param path - path of the gRPC call, e.g., `/emerald.Market/GetRates`
+    fn buckets(&self, path: &str) -> Vec<&Bucket> {
+        let method = self.methods.get(path).or_else(|| {
+            path.rsplit_once('/').and_then(|(service, _)| self.methods.get(&format!("{}/*", service)))
+        });
+        self.global.iter().chain(method).collect()
+    }
+
+    ///
+    /// Wait until the call is allowed by all its limits
+    async fn acquire(&self, path: &str) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        let mut reserved = Vec::new();
+        for bucket in self.buckets(path) {
+            match bucket.reserve(now, self.max_wait, self.max_queue) {
+                Ok(delay) => {
+                    wait = wait.max(delay);
+                    reserved.push((bucket, !delay.is_zero()));
+                }
+                Err(e) => {
+                    reserved.iter().for_each(|(bucket, waiting)| bucket.refund(*waiting));
+                    return Err(e)
+                }
+            }
+        }
+        let waiting = Waiting(reserved.into_iter().filter(|(_, waiting)| *waiting).map(|(bucket, _)| bucket).collect());
+        if !wait.is_zero() {
+            tracing::trace!("Call {} waits {:?} for the rate limit", path, wait);
+            tokio::time::sleep(wait).await;
+        }
+        drop(waiting);
+        Ok(())
+    }
+
+    fn on_response(&self, path: &str, code: Option<Code>) {
+        if self.adaptive && code == Some(Code::ResourceExhausted) {
+            let now = Instant::now();
+            self.buckets(path).iter().for_each(|bucket| bucket.decrease(now));
+        }
+    }
+
+    pub fn budget(&self) -> Vec<RateBudget> {
+        let now = Instant::now();
+        let mut budget: Vec<RateBudget> = self.global.iter().chain(self.methods.values())
+            .map(|bucket| bucket.budget(now))
+            .collect();
+        // the global one first, then the methods in a stable order
+        budget.sort_by(|a, b| (a.scope != GLOBAL_SCOPE, &a.scope).cmp(&(b.scope != GLOBAL_SCOPE, &b.scope)));
+        budget
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    state: Option<Arc<RateLimitState>>,
+}
+
+impl<S, B> Service<http::Request<Body>> for RateLimitService<S>
+where
+    S: Service<http::Request<Body>, Response = Response<B>> + Send + Clone + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Error>,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        // see AuthService::call for why the ready service is taken out
+        let inner_clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, inner_clone);
+
+        let Some(state) = self.state.clone() else {
+            return Box::pin(async move { inner.call(req).await.map_err(Into::into) })
+        };
+
+        let f = async move {
+            let path = req.uri().path().to_string();
+            state.acquire(&path).await?;
+            let response = inner.call(req).await.map_err(Into::<Error>::into)?;
+            state.on_response(&path, status_code(&response));
+            Ok(response)
+        };
+
+        Box::pin(f)
+    }
+}
+
+///
+/// A Rate Limit Layer for the Tokio Tower, which does nothing if the connection has no rate limits
+pub(crate) struct RateLimitLayer {
+    state: Option<Arc<RateLimitState>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(state: Option<Arc<RateLimitState>>) -> Self {
+        RateLimitLayer {
+            state
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RateLimitService {
+            inner: service,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::errors::Error;
+    use crate::ratelimit::{Bucket, RateLimit};
+
+    #[test]
+    fn test_reserve_tokens() {
+        let start = Instant::now();
+        let bucket = Bucket::new("*".to_string(), RateLimit::per_second(2), start);
+        let max_wait = Duration::from_secs(1);
+        assert_eq!(bucket.reserve(start, max_wait, 1), Ok(Duration::ZERO));
+        assert_eq!(bucket.reserve(start, max_wait, 1), Ok(Duration::ZERO));
+        // next token comes in 0.5s
+        assert_eq!(bucket.reserve(start, max_wait, 1), Ok(Duration::from_millis(500)));
+        // the queue is full
+        assert!(matches!(bucket.reserve(start, max_wait, 1), Err(Error::RateLimited(_))));
+        bucket.dequeue();
+        // the one after the queued comes in 1s, which is too long with a shorter max wait
+        assert!(matches!(bucket.reserve(start, Duration::from_millis(900), 1), Err(Error::RateLimited(_))));
+
+        let budget = bucket.budget(start + Duration::from_secs(1));
+        assert_eq!(budget.available, 1.0);
+        assert_eq!(budget.queued, 0);
+    }
+
+    #[test]
+    fn test_adapt_rate() {
+        let start = Instant::now();
+        let bucket = Bucket::new("*".to_string(), RateLimit::per_second(10), start);
+        bucket.decrease(start);
+        assert_eq!(bucket.budget(start).rate, 5.0);
+        // same burst of errors
+        bucket.decrease(start + Duration::from_millis(100));
+        assert!(bucket.budget(start + Duration::from_millis(100)).rate < 5.1);
+
+        // grows back over time
+        let later = start + Duration::from_secs(6);
+        let budget = bucket.budget(later);
+        assert!(budget.rate > 5.0 && budget.rate < 10.0, "rate: {}", budget.rate);
+        assert_eq!(bucket.budget(start + Duration::from_secs(60)).rate, 10.0);
+    }
+}
@@ -0,0 +1,90 @@
+use bytes::{Bytes, BytesMut};
+use futures::{FutureExt, StreamExt};
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
+use http_body::Frame;
+use tonic::{body::Body, codegen::http};
+
+///
+/// Max size of a body kept in memory to send it again. It's the default limit of a gRPC message, so a larger body is very unusual.
+const MAX_REPLAY_SIZE: usize = 4 * 1024 * 1024;
+
+///
+/// A request body prepared to be sent multiple times, if that is possible.
+pub(crate) enum RequestBody {
+    ///
+    /// The whole body read into memory, so it can be sent again (unary and server streaming calls)
+    Buffered(Bytes),
+    ///
+    /// A body that is still producing data (ex. client streaming calls), can be sent only once
+    Streaming(Body),
+}
+
+impl RequestBody {
+
+    ///
+    /// Read the body into memory if all of it is immediately available, which is the case for calls with a single request message.
+    /// Otherwise, it keeps the body streaming as it is, without waiting for the data.
+    pub fn read(body: Body) -> Self {
+        Self::read_limited(body, MAX_REPLAY_SIZE)
+    }
+
+    ///
+    /// Same as `read`, but a body larger than the limit is never buffered and can be sent only once.
+    ///
+    /// @param body - body of the request
+    /// @param limit - max size of the body to buffer, in bytes
+    fn read_limited(mut body: Body, limit: usize) -> Self {
+        let mut data = BytesMut::new();
+        let mut consumed = None;
+        loop {
+            if data.len() > limit {
+                break;
+            }
+            match body.frame().now_or_never() {
+                Some(None) => return RequestBody::Buffered(data.freeze()),
+                Some(Some(Ok(frame))) if frame.is_data() => data.extend_from_slice(&frame.into_data().unwrap()),
+                Some(Some(other)) => {
+                    consumed = Some(other);
+                    break
+                }
+                None => break,
+            }
+        }
+        // cannot buffer, so send what is already read and continue with the rest of the original body
+        let prefix = futures::stream::iter(
+            Some(Ok(Frame::data(data.freeze()))).into_iter().chain(consumed)
+        );
+        RequestBody::Streaming(Body::new(StreamBody::new(prefix.chain(BodyStream::new(body)))))
+    }
+
+    ///
+    /// Make a request with the body. Returns a copy of the body if it can be sent again.
+    pub fn into_request(self, parts: http::request::Parts) -> (http::Request<Body>, Option<Bytes>) {
+        match self {
+            RequestBody::Buffered(data) => {
+                (http::Request::from_parts(parts, Body::new(Full::new(data.clone()))), Some(data))
+            }
+            RequestBody::Streaming(body) => (http::Request::from_parts(parts, body), None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use tonic::body::Body;
+    use crate::replay::RequestBody;
+
+    #[tokio::test]
+    async fn test_stream_large_body() {
+        let body = Body::new(http_body_util::Full::new(Bytes::from(vec![1u8; 100])));
+        assert!(matches!(RequestBody::read_limited(body, 100), RequestBody::Buffered(data) if data.len() == 100));
+
+        let body = Body::new(http_body_util::Full::new(Bytes::from(vec![1u8; 101])));
+        match RequestBody::read_limited(body, 100) {
+            RequestBody::Streaming(body) => assert_eq!(body.collect().await.unwrap().to_bytes().len(), 101),
+            RequestBody::Buffered(_) => panic!("Large body is buffered"),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use tonic::{body::Body, codegen::http, Code};
+use http::Response;
+use tower::{Layer, Service};
+use crate::errors::Error;
+use crate::replay::RequestBody;
+
+///
+/// Default max number of attempts for a call, including the first one
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+///
+/// Default delay before the first retry
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+///
+/// Default limit for the delay between the attempts
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+///
+/// Header with the delay before the next attempt as requested by the server, in milliseconds. A negative value means the call must not be retried.
+/// See https://github.com/grpc/proposal/blob/master/A6-client-retries.md#pushback
+const PUSHBACK_HEADER: &str = "grpc-retry-pushback-ms";
+
+///
+/// Limits the retries when most of the calls fail, so the retries don't overload a server which is already in trouble.
+///
+/// The budget starts with `max_tokens` tokens. Each failed call takes one token, and each successful call gives back `token_ratio` of a token.
+/// Calls are retried only while the budget has more than half of `max_tokens`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryBudget {
+    pub max_tokens: u32,
+    pub token_ratio: f64,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            max_tokens: 10,
+            token_ratio: 0.1,
+        }
+    }
+}
+
+///
+/// Which calls are retried and how (see `EmeraldConn::with_retry`).
+///
+/// A call is retried only if its method is in the allowlist, i.e., it's safe to call it more than once, and it failed with one of the retryable codes (UNAVAILABLE or DEADLINE_EXCEEDED by default).
+/// For a server stream, only the start of the stream is retried, i.e., if the server rejected the call before sending any message.
+/// Calls with a client stream are never retried.
+///
+/// Between the attempts the client waits for an exponential backoff with a random jitter, or for the delay requested by the server in the `grpc-retry-pushback-ms` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    methods: HashSet<String>,
+    codes: Vec<Code>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    ///
+    /// The standard policy, which retries the idempotent methods of all the APIs enabled in the crate
+    fn default() -> Self {
+        let policy = RetryPolicy::empty();
+        #[cfg(feature = "client-auth")]
+        let policy = policy.with_all(crate::auth::IDEMPOTENT_METHODS);
+        #[cfg(feature = "client-market")]
+        let policy = policy.with_all(crate::market::IDEMPOTENT_METHODS);
+        policy
+    }
+}
+
+impl RetryPolicy {
+    ///
+    /// A policy with the default settings, but which has no methods to retry
+    pub fn empty() -> Self {
+        RetryPolicy {
+            methods: HashSet::new(),
+            codes: vec![Code::Unavailable, Code::DeadlineExceeded],
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            multiplier: 2.0,
+            budget: RetryBudget::default(),
+        }
+    }
+
+    ///
+    /// Allow retrying the method, or all methods of a service
+    ///
+    /// @param method - full path of the method, e.g., `/emerald.Market/GetRates`, or `/emerald.Market/*` for the whole service
+    pub fn with_method<S: ToString>(mut self, method: S) -> Self {
+        self.methods.insert(method.to_string());
+        self
+    }
+
+    ///
+    /// Allow retrying multiple methods, e.g., the predefined list of an API like `market::IDEMPOTENT_METHODS`
+    pub fn with_all(self, methods: &[&str]) -> Self {
+        methods.iter().fold(self, |policy, method| policy.with_method(method))
+    }
+
+    ///
+    /// Set the status codes which are retried
+    ///
+    /// @param codes - codes of the failed calls to retry
+    pub fn with_codes(self, codes: Vec<Code>) -> Self {
+        RetryPolicy {
+            codes,
+            ..self
+        }
+    }
+
+    ///
+    /// Set the max number of attempts for a call, including the first one
+    ///
+    /// @param attempts - max attempts, where 1 means no retries
+    pub fn with_max_attempts(self, attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: attempts.max(1),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the exponential backoff. The delay before the Nth retry is a random time up to `min(initial * multiplier^(N-1), max)`.
+    ///
+    /// @param initial - delay before the first retry
+    /// @param max - limit for the delay
+    /// @param multiplier - how much the delay grows after each attempt
+    pub fn with_backoff(self, initial: Duration, max: Duration, multiplier: f64) -> Self {
+        RetryPolicy {
+            initial_backoff: initial,
+            max_backoff: max,
+            multiplier: multiplier.max(1.0),
+            ..self
+        }
+    }
+
+    ///
+    /// Set the budget which limits the retries when most of the calls fail
+    pub fn with_budget(self, budget: RetryBudget) -> Self {
+        RetryPolicy {
+            budget,
+            ..self
+        }
+    }
+
+    ///
+    /// Check if the method can be retried
+    ///
+    /// @param path - path of the gRPC call, e.g., `/emerald.Market/GetRates`
+    pub fn is_retryable(&self, path: &str) -> bool {
+        self.methods.contains(path) || path.rsplit_once('/')
+            .is_some_and(|(service, _)| self.methods.contains(&format!("{}/*", service)))
+    }
+
+    ///
+    /// The longest delay before the retry, without the jitter
+    ///
+    /// @param retry - number of the retry, starting from 1
+    fn max_delay(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1).min(i32::MAX as u32) as i32);
+        self.initial_backoff.mul_f64(factor.min(u32::MAX as f64)).min(self.max_backoff)
+    }
+
+    ///
+    /// A random delay before the retry
+    ///
+    /// @param retry - number of the retry, starting from 1
+    fn delay(&self, retry: u32) -> Duration {
+        // with no system random it just waits for the full delay
+        let random = getrandom::u64().unwrap_or(u64::MAX);
+        self.max_delay(retry).mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+///
+/// Retry policy of a connection, with its budget shared by all its clones
+pub(crate) struct RetryState {
+    policy: RetryPolicy,
+    tokens: Mutex<f64>,
+}
+
+impl RetryState {
+    pub fn new(policy: RetryPolicy) -> Self {
+        RetryState {
+            tokens: Mutex::new(policy.budget.max_tokens as f64),
+            policy,
+        }
+    }
+
+    fn on_success(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.policy.budget.token_ratio).min(self.policy.budget.max_tokens as f64);
+    }
+
+    ///
+    /// Take a token for the failed call. Returns `true` if the budget still allows the retry.
+    fn on_failure(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens - 1.0).max(0.0);
+        *tokens > self.policy.budget.max_tokens as f64 / 2.0
+    }
+}
+
+///
+/// What the server asked to do with the failed call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pushback {
+    ///
+    /// No preference, use the backoff
+    None,
+    ///
+    /// Retry after the delay
+    After(Duration),
+    ///
+    /// Don't retry
+    Stop,
+}
+
+fn pushback<B>(response: &Response<B>) -> Pushback {
+    let Some(value) = response.headers().get(PUSHBACK_HEADER) else {
+        return Pushback::None
+    };
+    match value.to_str().ok().and_then(|value| value.parse::<i64>().ok()) {
+        Some(ms) if ms >= 0 => Pushback::After(Duration::from_millis(ms as u64)),
+        _ => Pushback::Stop,
+    }
+}
+
+///
+/// Get the gRPC status from the headers, which is there only if the call is failed before any response message (i.e., a _Trailers-Only_ response)
+pub(crate) fn status_code<B>(response: &Response<B>) -> Option<Code> {
+    response.headers().get("grpc-status")
+        .and_then(|status| status.to_str().ok())
+        .and_then(|status| status.parse::<i32>().ok())
+        .map(Code::from_i32)
+}
+
+#[derive(Clone)]
+pub struct RetryService<S> {
+    inner: S,
+    state: Option<Arc<RetryState>>,
+}
+
+impl<S, B> Service<http::Request<Body>> for RetryService<S>
+where
+    S: Service<http::Request<Body>, Response = Response<B>> + Send + Clone + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Error>,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        // see AuthService::call for why the ready service is taken out
+        let inner_clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, inner_clone);
+
+        let state = match &self.state {
+            Some(state) if state.policy.is_retryable(req.uri().path()) => state.clone(),
+            _ => return Box::pin(async move { inner.call(req).await.map_err(Into::into) }),
+        };
+
+        let f = async move {
+            let (parts, body) = req.into_parts();
+            let (req, replay) = RequestBody::read(body).into_request(parts.clone());
+            let mut result = inner.call(req).await.map_err(Into::<Error>::into);
+            let mut retry = 1;
+            loop {
+                let code = match &result {
+                    Ok(response) => status_code(response),
+                    // the connection to the server is failed
+                    Err(Error::Transport(_)) => Some(Code::Unavailable),
+                    // made by the client itself (e.g., a call rejected by the rate limit), so it would fail the same way again
+                    Err(_) => return result,
+                };
+                let code = match code {
+                    None | Some(Code::Ok) => {
+                        state.on_success();
+                        return result
+                    }
+                    Some(code) if !state.policy.codes.contains(&code) => return result,
+                    Some(code) => code,
+                };
+                if !state.on_failure() {
+                    tracing::debug!("Retry budget is exhausted, not retrying {}", parts.uri.path());
+                    return result
+                }
+                let Some(body) = replay.clone() else {
+                    return result
+                };
+                if retry >= state.policy.max_attempts {
+                    return result
+                }
+                let delay = match result.as_ref().map_or(Pushback::None, pushback) {
+                    Pushback::Stop => return result,
+                    Pushback::After(delay) => delay,
+                    Pushback::None => state.policy.delay(retry),
+                };
+                tracing::debug!("Call {} failed with {:?}, retry in {:?}", parts.uri.path(), code, delay);
+                tokio::time::sleep(delay).await;
+
+                retry += 1;
+                let (req, _) = RequestBody::Buffered(body).into_request(parts.clone());
+                futures::future::poll_fn(|cx| inner.poll_ready(cx)).await.map_err(Into::<Error>::into)?;
+                result = inner.call(req).await.map_err(Into::into);
+            }
+        };
+
+        Box::pin(f)
+    }
+}
+
+///
+/// A Retry Layer for the Tokio Tower, which does nothing if the connection has no retry policy
+pub(crate) struct RetryLayer {
+    state: Option<Arc<RetryState>>,
+}
+
+impl RetryLayer {
+    pub fn new(state: Option<Arc<RetryState>>) -> Self {
+        RetryLayer {
+            state
+        }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+
+    type Service = RetryService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RetryService {
+            inner: service,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tonic::codegen::http;
+    use crate::retry::{pushback, Pushback, RetryBudget, RetryPolicy, RetryState};
+
+    #[test]
+    fn test_find_retryable() {
+        let policy = RetryPolicy::empty()
+            .with_method("/emerald.Market/*")
+            .with_method("/emerald.Auth/WhoAmI");
+        assert!(policy.is_retryable("/emerald.Market/GetRates"));
+        assert!(policy.is_retryable("/emerald.Auth/WhoAmI"));
+        assert!(!policy.is_retryable("/emerald.Auth/Authenticate"));
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = RetryPolicy::empty()
+            .with_backoff(Duration::from_millis(100), Duration::from_secs(1), 2.0);
+        assert_eq!(policy.max_delay(1), Duration::from_millis(100));
+        assert_eq!(policy.max_delay(2), Duration::from_millis(200));
+        assert_eq!(policy.max_delay(4), Duration::from_millis(800));
+        assert_eq!(policy.max_delay(5), Duration::from_secs(1));
+        assert_eq!(policy.max_delay(100), Duration::from_secs(1));
+        for retry in 1..10 {
+            assert!(policy.delay(retry) <= policy.max_delay(retry));
+        }
+    }
+
+    #[test]
+    fn test_limit_by_budget() {
+        let state = RetryState::new(RetryPolicy::empty().with_budget(RetryBudget { max_tokens: 4, token_ratio: 0.5 }));
+        assert!(state.on_failure());
+        // 2 of 4 tokens left
+        assert!(!state.on_failure());
+        state.on_success();
+        state.on_success();
+        state.on_success();
+        assert!(state.on_failure());
+    }
+
+    #[test]
+    fn test_read_pushback() {
+        let response = http::Response::builder().header("grpc-retry-pushback-ms", "250").body(()).unwrap();
+        assert_eq!(pushback(&response), Pushback::After(Duration::from_millis(250)));
+        let response = http::Response::builder().header("grpc-retry-pushback-ms", "-1").body(()).unwrap();
+        assert_eq!(pushback(&response), Pushback::Stop);
+        let response = http::Response::builder().body(()).unwrap();
+        assert_eq!(pushback(&response), Pushback::None);
+    }
+}
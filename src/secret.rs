@@ -0,0 +1,66 @@
+use std::fmt::{Debug, Display, Formatter};
+use zeroize::Zeroize;
+
+///
+/// A sensitive value, such as an API token, a JWT or a refresh token.
+///
+/// It never shows the value in `Debug` or `Display`, so it's safe to log a structure that contains it,
+/// and it wipes the memory when dropped. Use `expose()` to access the actual value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<S: Into<String>>(value: S) -> Self {
+        Secret(value.into())
+    }
+
+    ///
+    /// The actual value. Avoid keeping a copy of it longer than needed, the copy is not protected.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "***")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::Secret;
+
+    #[test]
+    fn test_redacted_in_output() {
+        let secret = Secret::new("secret_token");
+        assert_eq!(format!("{:?}", secret), "Secret(***)");
+        assert_eq!(format!("{}", secret), "***");
+        assert_eq!(format!("{:?}", Some(secret.clone())), "Some(Secret(***))");
+        assert_eq!(secret.expose(), "secret_token");
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+///
+/// A JWT saved to reuse it later, possibly by another process
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub jwt: String,
+    pub refresh: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Debug for StoredToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // the tokens are redacted the same way as `crate::secret::Secret`
+        f.debug_struct("StoredToken")
+            .field("jwt", &"***")
+            .field("refresh", &"***")
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+///
+/// Storage for the received JWT tokens, so they can be reused instead of making a new authentication.
+pub trait TokenStore: Send + Sync + Debug {
+    fn load(&self, key: &str) -> Option<StoredToken>;
+    fn save(&self, key: &str, token: &StoredToken);
+}
+
+///
+/// Make a key for the token in a store, which is specific to the API endpoint and the secret.
+/// The secret itself is never saved, only its fingerprint.
+///
+/// @param endpoint - URI of the API
+/// @param secret - secret the token is received for
+pub fn store_key(endpoint: &str, secret: &str) -> String {
+    let hash = Sha256::digest(secret.as_bytes());
+    let fingerprint = hash[..16].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}#{}", endpoint, fingerprint)
+}
+
+///
+/// Keeps the tokens in a JSON file readable only by the current user.
+/// A failure to read or write the file is logged and otherwise ignored, because the client can always make a new authentication.
+#[derive(Debug)]
+pub struct FileTokenStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileTokenStore {
+    ///
+    /// Use the file at the specified path. The file is created on first save.
+    ///
+    /// @param path - path to the file
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileTokenStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read_all(&self) -> HashMap<String, StoredToken> {
+        match std::fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                tracing::warn!("Invalid token store at {}: {}", self.path.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                tracing::warn!("Cannot read token store at {}: {}", self.path.display(), e);
+                HashMap::new()
+            }
+        }
+    }
+
+    fn write_all(&self, tokens: &HashMap<String, StoredToken>) -> std::io::Result<()> {
+        crate::private_file::write(&self.path, &serde_json::to_vec(tokens)?)
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self, key: &str) -> Option<StoredToken> {
+        let _lock = self.lock.lock().unwrap();
+        self.read_all().remove(key)
+    }
+
+    fn save(&self, key: &str, token: &StoredToken) {
+        let _lock = self.lock.lock().unwrap();
+        // tokens with an expired JWT are kept, because their refresh token may still be valid
+        let mut tokens = self.read_all();
+        tokens.insert(key.to_string(), token.clone());
+        if let Err(e) = self.write_all(&tokens) {
+            tracing::warn!("Cannot write token store at {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use crate::store::{store_key, FileTokenStore, StoredToken, TokenStore};
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("emerald-api-store-{}.json", std::process::id()));
+        let store = FileTokenStore::new(&path);
+        let token = StoredToken {
+            jwt: "jwt_001".to_string(),
+            refresh: "refresh_001".to_string(),
+            expires_at: Utc::now() + Duration::hours(1),
+        };
+        let key = store_key("https://api.emrld.io", "secret_token");
+        store.save(&key, &token);
+
+        let store = FileTokenStore::new(&path);
+        assert_eq!(store.load(&key), Some(token));
+        assert_eq!(store.load(&store_key("https://api.emrld.io", "other_secret")), None);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("secret_token"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keep_expired_jwt() {
+        let dir = std::env::temp_dir().join(format!("emerald-api-store-expired-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tokens.json");
+        let store = FileTokenStore::new(&path);
+        let expired = StoredToken {
+            jwt: "jwt_001".to_string(),
+            refresh: "refresh_001".to_string(),
+            expires_at: Utc::now() - Duration::hours(1),
+        };
+        let key_expired = store_key("https://api.emrld.io", "secret_1");
+        store.save(&key_expired, &expired);
+        let key_active = store_key("https://api.emrld.io", "secret_2");
+        store.save(&key_active, &StoredToken {
+            jwt: "jwt_002".to_string(),
+            refresh: "refresh_002".to_string(),
+            expires_at: Utc::now() + Duration::hours(1),
+        });
+
+        assert_eq!(store.load(&key_expired), Some(expired));
+        // only the store itself is left, without temp files
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use chrono::{DateTime, Utc};
+use crate::conn::EmeraldConn;
+use crate::conn::ApiChannel;
+use crate::creds::Credentials;
+use crate::errors::{CredentialsError, Error};
+use crate::proto::auth::{auth_client::AuthClient, DeleteTokenRequest, IssueTokenRequest, ListTokensRequest, TokenDetails};
+use crate::secret::Secret;
+
+///
+/// An API token of the current user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub user_id: String,
+    pub created_at: Option<DateTime<Utc>>,
+    /// expiration time, or `None` if the token never expires
+    pub expires_at: Option<DateTime<Utc>>,
+    /// scopes the token is limited to, or empty if it's not limited
+    pub scopes: Vec<String>,
+}
+
+impl From<TokenDetails> for ApiToken {
+    fn from(value: TokenDetails) -> Self {
+        ApiToken {
+            id: value.token_id,
+            name: value.display_name,
+            user_id: value.user_id,
+            created_at: from_millis(value.created_at),
+            expires_at: from_millis(value.expire_at),
+            scopes: value.scopes,
+        }
+    }
+}
+
+///
+/// A newly issued API token. The secret is available only at this moment, and cannot be requested later.
+#[derive(Debug, Clone)]
+pub struct IssuedToken {
+    pub id: String,
+    pub secret: Secret,
+}
+
+///
+/// Result of `TokenManager::rotate`
+#[derive(Debug, Clone)]
+pub struct RotatedToken {
+    /// the new token, which is already used by the connection
+    pub token: IssuedToken,
+    /// whether the old token is deleted. If not, it's still valid and should be deleted manually.
+    pub revoked: bool,
+}
+
+///
+/// Manages the API tokens of the user the connection is authenticated for
+pub struct TokenManager {
+    conn: EmeraldConn,
+    client: AuthClient<ApiChannel>,
+}
+
+impl TokenManager {
+    pub fn new(conn: &EmeraldConn) -> Self {
+        TokenManager {
+            conn: conn.clone(),
+            client: crate::auth::connect(conn),
+        }
+    }
+
+    ///
+    /// Issue a new API token
+    ///
+    /// @param name - a name to distinguish the token
+    /// @param expires_at - expiration time, or `None` for a token that never expires. Must be after 1970-01-01.
+    /// @param scopes - scopes to limit the token to; empty for no limits
+    pub async fn issue<S: ToString>(&self, name: S, expires_at: Option<DateTime<Utc>>, scopes: Vec<String>) -> Result<IssuedToken, Error> {
+        let expire_at = match expires_at {
+            None => 0,
+            // zero means no expiration, so it cannot be used for the exact 1970-01-01 either
+            Some(ts) => u64::try_from(ts.timestamp_millis()).ok()
+                .filter(|ms| *ms > 0)
+                .ok_or_else(|| Error::InvalidArgument(format!("Expiration time {} is before 1970", ts)))?,
+        };
+        let response = self.client.clone().issue_token(IssueTokenRequest {
+            display_name: name.to_string(),
+            expire_at,
+            scopes,
+            ..Default::default()
+        }).await?.into_inner();
+        Ok(IssuedToken {
+            id: response.token_id,
+            secret: Secret::new(response.access_token),
+        })
+    }
+
+    ///
+    /// List all API tokens of the user
+    pub async fn list(&self) -> Result<Vec<ApiToken>, Error> {
+        let response = self.client.clone().list_tokens(ListTokensRequest::default()).await?.into_inner();
+        Ok(response.tokens.into_iter().map(ApiToken::from).collect())
+    }
+
+    ///
+    /// Delete the API token, so it cannot be used anymore. Returns `false` if there is no such token.
+    ///
+    /// @param id - id of the token
+    pub async fn revoke(&self, id: &str) -> Result<bool, Error> {
+        let response = self.client.clone().delete_token(DeleteTokenRequest {
+            token_id: id.to_string(),
+            ..Default::default()
+        }).await?.into_inner();
+        Ok(response.deleted)
+    }
+
+    ///
+    /// Replace the API token with a new one: issue it with the same name and scopes, switch the connection to it (keeping the requested scopes, etc.), and delete the old token.
+    /// If the old token had an expiration time, the new one is valid for the same period starting from now.
+    ///
+    /// Once the new token is issued, the method doesn't fail, because the caller must save the new secret anyway.
+    /// A failure to delete the old token is reported with `RotatedToken::revoked`.
+    ///
+    /// Fails with `Error::TokenNotFound` if the user has no token with the id.
+    /// Fails with `CredentialsError::Provider` if the connection uses a `CredentialsProvider`, because it would keep giving the old secret.
+    /// In that case, issue a new token, update the source of the provider, and revoke the old token separately.
+    ///
+    /// @param id - id of the current token
+    pub async fn rotate(&self, id: &str) -> Result<RotatedToken, Error> {
+        if let Credentials::Provided { .. } = self.conn.get_credentials() {
+            return Err(Error::Credentials(CredentialsError::Provider("Cannot rotate a token given by a credentials provider".to_string())));
+        }
+        let current = self.list().await?
+            .into_iter()
+            .find(|token| token.id == id)
+            .ok_or_else(|| Error::TokenNotFound(id.to_string()))?;
+        let expires_at = match (current.created_at, current.expires_at) {
+            (Some(created_at), Some(expires_at)) => Some(Utc::now() + (expires_at - created_at)),
+            _ => current.expires_at,
+        };
+        let token = self.issue(&current.name, expires_at, current.scopes).await?;
+
+        let credentials = self.conn.get_credentials().with_secret(token.secret.clone());
+        self.conn.set_credentials(credentials);
+
+        let revoked = match self.revoke(id).await {
+            Ok(deleted) => deleted,
+            Err(e) => {
+                tracing::warn!("Failed to delete the old token {}: {}", id, e);
+                false
+            }
+        };
+        Ok(RotatedToken {
+            token,
+            revoked,
+        })
+    }
+}
+
+fn from_millis(ts: u64) -> Option<DateTime<Utc>> {
+    if ts == 0 {
+        return None;
+    }
+    DateTime::from_timestamp_millis(ts as i64)
+}
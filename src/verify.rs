@@ -0,0 +1,271 @@
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::future::{Either, Ready, ready};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use tonic::{Status, codegen::http};
+use tower::{Layer, Service};
+use crate::claims::{JwtClaims, Payload};
+use crate::errors::Error;
+use crate::policy::{AuthPolicy, AuthRequirement};
+
+///
+/// Default tolerance for the difference between the server clock and the clock of the JWT issuer
+pub const DEFAULT_LEEWAY: Duration = Duration::from_secs(60);
+
+///
+/// A key to verify the JWT signature
+#[derive(Clone)]
+pub struct VerificationKey {
+    /// if set, the key is used only for JWTs with the same `kid` in the header
+    key_id: Option<String>,
+    key: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+impl VerificationKey {
+    ///
+    /// A shared secret for JWTs signed with HMAC (`HS256`, `HS384` or `HS512`)
+    ///
+    /// @param secret - the secret used to sign the JWT
+    pub fn hmac(secret: &[u8]) -> Self {
+        VerificationKey {
+            key_id: None,
+            key: DecodingKey::from_secret(secret),
+            algorithms: vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+        }
+    }
+
+    ///
+    /// An RSA public key for JWTs signed with `RS256`, `RS384`, `RS512`, `PS256`, `PS384` or `PS512`
+    ///
+    /// @param pem - public key in PEM format
+    pub fn rsa_pem(pem: &[u8]) -> Result<Self, Error> {
+        let key = DecodingKey::from_rsa_pem(pem)
+            .map_err(|e| Error::InvalidKey(format!("RSA key: {}", e)))?;
+        Ok(VerificationKey {
+            key_id: None,
+            key,
+            algorithms: vec![
+                Algorithm::RS256, Algorithm::RS384, Algorithm::RS512,
+                Algorithm::PS256, Algorithm::PS384, Algorithm::PS512,
+            ],
+        })
+    }
+
+    ///
+    /// An EC public key for JWTs signed with `ES256` or `ES384`
+    ///
+    /// @param pem - public key in PEM format
+    pub fn ec_pem(pem: &[u8]) -> Result<Self, Error> {
+        let key = DecodingKey::from_ec_pem(pem)
+            .map_err(|e| Error::InvalidKey(format!("EC key: {}", e)))?;
+        Ok(VerificationKey {
+            key_id: None,
+            key,
+            algorithms: vec![Algorithm::ES256, Algorithm::ES384],
+        })
+    }
+
+    ///
+    /// Use the key only for JWTs with the specified Key ID (`kid`) in the header.
+    /// A JWT without `kid` can be verified by any key.
+    pub fn with_key_id<S: ToString>(self, key_id: S) -> Self {
+        VerificationKey {
+            key_id: Some(key_id.to_string()),
+            ..self
+        }
+    }
+
+    fn accepts(&self, header: &jsonwebtoken::Header) -> bool {
+        let same_id = match (&header.kid, &self.key_id) {
+            (Some(kid), Some(key_id)) => kid == key_id,
+            _ => true,
+        };
+        same_id && self.algorithms.contains(&header.alg)
+    }
+}
+
+///
+/// Verifies the JWTs issued for the Emerald API, or for any compatible service
+#[derive(Clone)]
+pub struct JwtVerifier {
+    keys: Vec<VerificationKey>,
+    leeway: Duration,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtVerifier {
+    ///
+    /// Verify JWTs signed by any of the keys
+    ///
+    /// @param keys - keys to verify the signature
+    pub fn new(keys: Vec<VerificationKey>) -> Self {
+        JwtVerifier {
+            keys,
+            leeway: DEFAULT_LEEWAY,
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    ///
+    /// Set how long a JWT is accepted after its expiration time. Default is `DEFAULT_LEEWAY`.
+    pub fn with_leeway(self, leeway: Duration) -> Self {
+        JwtVerifier {
+            leeway,
+            ..self
+        }
+    }
+
+    ///
+    /// Accept only JWTs issued by the specified issuer (`iss`)
+    pub fn with_issuer<S: ToString>(self, issuer: S) -> Self {
+        JwtVerifier {
+            issuer: Some(issuer.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Accept only JWTs issued for the specified audience (`aud`).
+    /// NOTE: without it, a JWT that has an audience is always rejected
+    pub fn with_audience<S: ToString>(self, audience: S) -> Self {
+        JwtVerifier {
+            audience: Some(audience.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Verify the JWT and get its claims. The JWT must be signed by one of the keys and must have an expiration time.
+    ///
+    /// @param jwt - JWT in the compact form
+    pub fn verify(&self, jwt: &str) -> Result<JwtClaims, Status> {
+        let header = jsonwebtoken::decode_header(jwt)
+            .map_err(|e| Status::unauthenticated(format!("Malformed JWT: {}", e)))?;
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway.as_secs();
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        for key in self.keys.iter().filter(|key| key.accepts(&header)) {
+            match jsonwebtoken::decode::<Payload>(jwt, &key.key, &validation) {
+                Ok(data) => return Ok(data.claims.into()),
+                // may be signed by another key
+                Err(e) if *e.kind() == ErrorKind::InvalidSignature => continue,
+                Err(e) => return Err(Status::unauthenticated(format!("Invalid JWT: {}", e))),
+            }
+        }
+        Err(Status::unauthenticated("JWT is not signed by a known key"))
+    }
+}
+
+///
+/// Get the token from the `authorization: Bearer <token>` header
+pub(crate) fn bearer_token(headers: &http::HeaderMap) -> Result<&str, Status> {
+    let value = headers.get(http::header::AUTHORIZATION)
+        .ok_or_else(|| Status::unauthenticated("Missing authorization header"))?;
+    let value = value.to_str()
+        .map_err(|_| Status::unauthenticated("Invalid authorization header"))?;
+    match value.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(token.trim()),
+        _ => Err(Status::unauthenticated("Authorization is not a Bearer token")),
+    }
+}
+
+///
+/// A server service that accepts only requests with a valid JWT, and puts its `JwtClaims` into the request extensions.
+/// Other requests are rejected with UNAUTHENTICATED status without calling the inner service.
+///
+/// The methods which are `Public` in the policy are passed without any check, and the `Optional` ones are checked only if they have a JWT.
+/// A handler can read the claims with `request.extensions().get::<JwtClaims>()`.
+#[derive(Clone)]
+pub struct JwtVerifyService<S> {
+    inner: S,
+    verifier: Arc<JwtVerifier>,
+    policy: Arc<AuthPolicy>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for JwtVerifyService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<ReqBody>) -> Self::Future {
+        let skip = match self.policy.requirement(req.uri().path()) {
+            AuthRequirement::Required => false,
+            AuthRequirement::Optional => !req.headers().contains_key(http::header::AUTHORIZATION),
+            AuthRequirement::Public => true,
+        };
+        if skip {
+            return Either::Left(self.inner.call(req));
+        }
+        match bearer_token(req.headers()).and_then(|jwt| self.verifier.verify(jwt)) {
+            Ok(claims) => {
+                req.extensions_mut().insert(claims);
+                Either::Left(self.inner.call(req))
+            }
+            Err(status) => {
+                tracing::debug!("Request to {} rejected: {}", req.uri().path(), status.message());
+                Either::Right(ready(Ok(status.into_http())))
+            }
+        }
+    }
+}
+
+///
+/// A Tower layer to verify the JWT on a server, see `JwtVerifyService`.
+/// Use it with `tonic::transport::Server::builder().layer(JwtVerifyLayer::new(verifier))`
+#[derive(Clone)]
+pub struct JwtVerifyLayer {
+    verifier: Arc<JwtVerifier>,
+    policy: Arc<AuthPolicy>,
+}
+
+impl JwtVerifyLayer {
+    ///
+    /// Verify the JWT for all methods. Use `with_policy` to serve some of them without a JWT.
+    pub fn new(verifier: JwtVerifier) -> Self {
+        JwtVerifyLayer {
+            verifier: Arc::new(verifier),
+            policy: Arc::new(AuthPolicy::empty()),
+        }
+    }
+
+    ///
+    /// Set which methods can be called without a JWT, e.g., `AuthPolicy::empty().with_all(auth::AUTH_POLICY)` to serve Authenticate and Refresh behind the same layer.
+    ///
+    /// @param policy - requirements for the methods
+    pub fn with_policy(self, policy: AuthPolicy) -> Self {
+        JwtVerifyLayer {
+            policy: Arc::new(policy),
+            ..self
+        }
+    }
+}
+
+impl<S> Layer<S> for JwtVerifyLayer {
+    type Service = JwtVerifyService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        JwtVerifyService {
+            inner: service,
+            verifier: self.verifier.clone(),
+            policy: self.policy.clone(),
+        }
+    }
+}
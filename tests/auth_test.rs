@@ -3,7 +3,7 @@ mod on_mock {
     use emerald_api::{
         conn::EmeraldConn,
         auth::connect,
-        creds::{Credentials, JwtState},
+        creds::{Credentials, CredentialsEvent, JwtState},
         proto::auth::{
             auth_server::Auth, AuthRequest, AuthResponse,
             IssueTokenRequest, IssuedTokenResponse,
@@ -13,11 +13,18 @@ mod on_mock {
             DeleteTokenRequest, DeleteTokenResponse
         },
     };
-    use tonic::{transport::Server, Request, Response, Status};
+    use tonic::{transport::{Channel, Server}, Code, Request, Response, Status};
     use std::net::SocketAddr;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
-    use chrono::Utc;
+    use chrono::{Duration, Utc};
+    use emerald_api::errors::{CredentialsError, Error};
+    use emerald_api::provider::{LiteralProvider, ProvidedCredentials};
+    use emerald_api::store::FileTokenStore;
+    use emerald_api::policy::{AuthPolicy, AuthRequirement};
+    use emerald_api::retry::RetryPolicy;
+    use emerald_api::ratelimit::{RateLimit, RateLimitPolicy};
+    use emerald_api::balance::Selection;
 
     struct MockAuthService {
         responses: Vec<AuthResponse>,
@@ -66,6 +73,22 @@ mod on_mock {
         async fn who_am_i(&self, request: Request<WhoAmIRequest>) -> Result<Response<WhoAmIResponse>, Status> {
             self.request_count.fetch_add(1, Ordering::Relaxed);
             println!("Received request: {:?}", request);
+            if request.metadata().get("authorization").is_some_and(|auth| auth == "Bearer jwt_revoked") {
+                return Err(Status::unauthenticated("JWT is revoked"));
+            }
+            // the first two calls with this JWT fail as if the server is overloaded
+            if request.metadata().get("authorization").is_some_and(|auth| auth == "Bearer jwt_overloaded")
+                && self.request_count.load(Ordering::Relaxed) <= 2 {
+                let mut status = Status::unavailable("Server is overloaded");
+                status.metadata_mut().insert("grpc-retry-pushback-ms", "200".parse().unwrap());
+                return Err(status);
+            }
+            if request.metadata().get("authorization").is_some_and(|auth| auth == "Bearer jwt_exhausted") {
+                return Err(Status::resource_exhausted("Quota exceeded"));
+            }
+            if request.metadata().get("authorization").is_some_and(|auth| auth == "Bearer jwt_slow") {
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            }
             Ok(Response::new(WhoAmIResponse {
                 is_authenticated: true,
                 user_id: "user_001".to_string(),
@@ -92,6 +115,31 @@ mod on_mock {
         tracing::subscriber::set_default(subscriber)
     }
 
+    ///
+    /// Start the mock server on the specified address and connect to it
+    async fn start_server(addr: SocketAddr, mock_service: MockAuthService) -> Channel {
+        tokio::spawn(async move {
+            let serve_future = Server::builder()
+                .add_service(emerald_api::proto::auth::auth_server::AuthServer::new(mock_service))
+                .serve(addr)
+                .await;
+            if let Err(e) = serve_future {
+                eprintln!("Failed to start server: {}", e);
+            } else {
+                eprintln!("Server stopped");
+            }
+        });
+
+        println!("Waiting for server to start...");
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+        Channel::from_shared(format!("http://{}", addr)).unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn test_authentication() {
         let _ = enable_tracing();
@@ -145,14 +193,17 @@ mod on_mock {
             Credentials::None => {
                 panic!("No credential");
             }
+            Credentials::StaticJwt { .. } | Credentials::Provided { .. } => {
+                panic!("Unexpected credentials type");
+            }
             Credentials::Token(jwt_state) => {
                 match jwt_state {
                     JwtState::Initial { .. } => {
                         panic!("Still the initial state");
                     }
-                    JwtState::Authenticated { jwt, refresh, expires_at: _ } => {
-                        assert_eq!(jwt, "jwt_001");
-                        assert_eq!(refresh, "refresh_001");
+                    JwtState::Authenticated { jwt, refresh, .. } => {
+                        assert_eq!(jwt.expose(), "jwt_001");
+                        assert_eq!(refresh.expose(), "refresh_001");
                     }
                 }
             }
@@ -236,8 +287,8 @@ mod on_mock {
         // Check the initial JWT
         match conn.get_credentials() {
             Credentials::Token(JwtState::Authenticated { jwt, refresh, .. }) => {
-                assert_eq!(jwt, "jwt_001");
-                assert_eq!(refresh, "refresh_001");
+                assert_eq!(jwt.expose(), "jwt_001");
+                assert_eq!(refresh.expose(), "refresh_001");
             }
             _ => panic!("Unexpected credential state"),
         }
@@ -254,8 +305,8 @@ mod on_mock {
         // Check the refreshed JWT
         match conn.get_credentials() {
             Credentials::Token(JwtState::Authenticated { jwt, refresh, .. }) => {
-                assert_eq!(jwt, "jwt_002");
-                assert_eq!(refresh, "refresh_002");
+                assert_eq!(jwt.expose(), "jwt_002");
+                assert_eq!(refresh.expose(), "refresh_002");
             }
             _ => panic!("Unexpected credential state"),
         }
@@ -264,5 +315,633 @@ mod on_mock {
         assert_eq!(request_count.load(Ordering::Relaxed), 4);
     }
 
-}
+    #[tokio::test]
+    async fn test_single_authentication_for_concurrent_requests() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9093".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: 1800000000000, // Some fixed timestamp
+                    ..Default::default()
+                }
+            ],
+        };
+
+        tokio::spawn(async move {
+            let serve_future = Server::builder()
+                .add_service(emerald_api::proto::auth::auth_server::AuthServer::new(mock_service))
+                .serve(addr)
+                .await;
+            if let Err(e) = serve_future {
+                eprintln!("Failed to start server: {}", e);
+            } else {
+                eprintln!("Server stopped");
+            }
+        });
+
+        println!("Waiting for server to start...");
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+        let channel = tonic::transport::Channel::from_static("http://127.0.0.1:9093")
+            .connect()
+            .await
+            .unwrap();
+
+        let credentials = Credentials::token("secret_token");
+        let conn = EmeraldConn::new(channel, credentials);
+
+        let calls = (0..20).map(|_| {
+            let mut auth_client = connect(&conn);
+            tokio::spawn(async move {
+                auth_client.who_am_i(WhoAmIRequest {}).await
+            })
+        }).collect::<Vec<_>>();
+
+        for call in calls {
+            let me = call.await.unwrap().unwrap().into_inner();
+            assert_eq!(me.user_id, "user_001");
+        }
+
+        // a single auth + 20 who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 21);
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9094".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3000, // Expires in 3 seconds
+                    ..Default::default()
+                },
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_002".to_string(),
+                    refresh_token: "refresh_002".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        tokio::spawn(async move {
+            let serve_future = Server::builder()
+                .add_service(emerald_api::proto::auth::auth_server::AuthServer::new(mock_service))
+                .serve(addr)
+                .await;
+            if let Err(e) = serve_future {
+                eprintln!("Failed to start server: {}", e);
+            } else {
+                eprintln!("Server stopped");
+            }
+        });
+
+        println!("Waiting for server to start...");
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+        let channel = tonic::transport::Channel::from_static("http://127.0.0.1:9094")
+            .connect()
+            .await
+            .unwrap();
+
+        let credentials = Credentials::token("secret_token");
+        let conn = EmeraldConn::new(channel, credentials)
+            .with_refresh_ahead(std::time::Duration::from_secs(2))
+            .with_background_refresh();
+
+        let mut auth_client = connect(&conn);
+
+        // First request - should trigger authentication
+        let me = auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(me.into_inner().user_id, "user_001");
+
+        // The token should be refreshed 2 seconds before the expiration, i.e., in a second
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+        match conn.get_credentials() {
+            Credentials::Token(JwtState::Authenticated { jwt, refresh, .. }) => {
+                assert_eq!(jwt.expose(), "jwt_002");
+                assert_eq!(refresh.expose(), "refresh_002");
+            }
+            _ => panic!("Unexpected credential state"),
+        }
+
+        // auth + who_am_i + refresh, without any other API call
+        assert_eq!(request_count.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_again_if_refresh_rejected() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9095".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_revoked".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 1000, // Expires in 1 second
+                    ..Default::default()
+                },
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_002".to_string(),
+                    refresh_token: "refresh_002".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        let credentials = Credentials::token("secret_token");
+        let conn = EmeraldConn::new(channel, credentials)
+            .with_refresh_ahead(std::time::Duration::ZERO);
+
+        let mut auth_client = connect(&conn);
+
+        auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+
+        // Wait for the token to expire
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+        // Second request - the refresh token is rejected, so it should authenticate with the original secret
+        let me_2 = auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(me_2.into_inner().user_id, "user_001");
+
+        match conn.get_credentials() {
+            Credentials::Token(JwtState::Authenticated { jwt, refresh, secret, .. }) => {
+                assert_eq!(jwt.expose(), "jwt_002");
+                assert_eq!(refresh.expose(), "refresh_002");
+                assert_eq!(secret.expose(), "secret_token");
+            }
+            _ => panic!("Unexpected credential state"),
+        }
+
+        // auth + who_am_i + rejected refresh + auth + who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_again_if_jwt_rejected() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9096".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_revoked".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_002".to_string(),
+                    refresh_token: "refresh_002".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        let credentials = Credentials::token("secret_token");
+        let conn = EmeraldConn::new(channel, credentials);
 
+        let mut auth_client = connect(&conn);
+
+        // the first JWT is rejected by the server, but the client should transparently get a new one and repeat the call
+        let me = auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(me.into_inner().user_id, "user_001");
+
+        match conn.get_credentials() {
+            Credentials::Token(JwtState::Authenticated { jwt, .. }) => {
+                assert_eq!(jwt.expose(), "jwt_002");
+            }
+            _ => panic!("Unexpected credential state"),
+        }
+
+        // auth + rejected who_am_i + auth + who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_static_jwt() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9097".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        // a valid JWT is used as is, without any auth call
+        let conn = EmeraldConn::new(channel.clone(), Credentials::jwt("jwt_static"));
+        let me = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(me.into_inner().user_id, "user_001");
+        assert_eq!(request_count.load(Ordering::Relaxed), 1);
+
+        // a rejected JWT is not replaced with a new one
+        let conn = EmeraldConn::new(channel.clone(), Credentials::jwt("jwt_revoked"));
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        let err = Error::from_status(&status);
+        assert_eq!(err, Some(&Error::Credentials(CredentialsError::JwtRejected)));
+        assert_eq!(request_count.load(Ordering::Relaxed), 2);
+
+        // an expired JWT is not even sent
+        let conn = EmeraldConn::new(channel, Credentials::jwt_until("jwt_static", Utc::now() - Duration::minutes(1)));
+        let mut events = conn.subscribe();
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        let err = Error::from_status(&status);
+        assert_eq!(err, Some(&Error::Credentials(CredentialsError::JwtExpired)));
+        assert_eq!(request_count.load(Ordering::Relaxed), 2);
+
+        // the expiration is reported only once
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        assert_eq!(events.try_recv(), Ok(CredentialsEvent::Expired));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_provided_credentials() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9098".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        let provider = LiteralProvider::new(ProvidedCredentials::Secret("secret_token".into()));
+        let conn = EmeraldConn::new(channel, Credentials::provider(provider));
+
+        let mut auth_client = connect(&conn);
+        auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+
+        match conn.get_credentials() {
+            Credentials::Provided { state: Some(JwtState::Authenticated { jwt, secret, .. }), .. } => {
+                assert_eq!(jwt.expose(), "jwt_001");
+                assert_eq!(secret.expose(), "secret_token");
+            }
+            _ => panic!("Unexpected credential state"),
+        }
+
+        // auth with the provided secret + 2 who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reuse_stored_jwt() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9099".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+        let path = std::env::temp_dir().join(format!("emerald-api-auth-test-{}.json", std::process::id()));
+
+        let conn = EmeraldConn::new(channel.clone(), Credentials::token("secret_token"))
+            .with_token_store(FileTokenStore::new(&path));
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        // auth + who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 2);
+
+        // as if it's a new process, which should use the same JWT without authentication
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"))
+            .with_token_store(FileTokenStore::new(&path));
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        // + who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 3);
+
+        match conn.get_credentials() {
+            Credentials::Token(JwtState::Authenticated { jwt, refresh, .. }) => {
+                assert_eq!(jwt.expose(), "jwt_001");
+                assert_eq!(refresh.expose(), "refresh_001");
+            }
+            _ => panic!("Unexpected credential state"),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keep_settings_with_credentials() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9123".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+        let path = std::env::temp_dir().join(format!("emerald-api-settings-test-{}.json", std::process::id()));
+
+        // the credentials are set after the other options
+        let conn = EmeraldConn::new(channel.clone(), Credentials::unauthenticated())
+            .with_token_store(FileTokenStore::new(&path))
+            .with_auth_policy(AuthPolicy::empty().with("/emerald.Auth/WhoAmI", AuthRequirement::Optional))
+            .with_credentials(Credentials::token("secret_token"));
+        // no auth for an optional method
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(request_count.load(Ordering::Relaxed), 1);
+
+        let conn = conn.with_auth_policy(AuthPolicy::empty());
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        // + auth + who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 3);
+
+        // the JWT is saved to the store
+        let conn = EmeraldConn::new(channel, Credentials::unauthenticated())
+            .with_token_store(FileTokenStore::new(&path))
+            .with_credentials(Credentials::token("secret_token"));
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        // + who_am_i
+        assert_eq!(request_count.load(Ordering::Relaxed), 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_credentials_events() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9100".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_001".to_string(),
+                    refresh_token: "refresh_001".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 1000, // Expires in 1 second
+                    ..Default::default()
+                },
+                AuthResponse {
+                    status: 0,
+                    access_token: "jwt_002".to_string(),
+                    refresh_token: "refresh_002".to_string(),
+                    expires_at: Utc::now().timestamp_millis() as u64 + 3600000, // Expires in 1 hour
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        let conn = EmeraldConn::new(channel.clone(), Credentials::token("invalid_token"));
+        let mut events = conn.subscribe();
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        match events.try_recv() {
+            Ok(CredentialsEvent::Denied { deny_message, .. }) => assert_eq!(deny_message, "Invalid secret token"),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"))
+            .with_refresh_ahead(std::time::Duration::ZERO);
+        let mut events = conn.subscribe();
+        let mut auth_client = connect(&conn);
+
+        auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Authenticated { .. })));
+
+        // Wait for the token to expire
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+        auth_client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(events.try_recv(), Ok(CredentialsEvent::Expired));
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Refreshed { .. })));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_unavailable() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9120".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        // not retried by default
+        let conn = EmeraldConn::new(channel.clone(), Credentials::jwt("jwt_overloaded"));
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(request_count.load(Ordering::Relaxed), 1);
+
+        // retried after the delay requested by the server
+        let conn = EmeraldConn::new(channel.clone(), Credentials::jwt("jwt_overloaded"))
+            .with_retry(RetryPolicy::default());
+        let started = std::time::Instant::now();
+        let me = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(me.into_inner().user_id, "user_001");
+        assert_eq!(request_count.load(Ordering::Relaxed), 3);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+
+        // a method not in the allowlist is not retried
+        request_count.store(0, Ordering::Relaxed);
+        let conn = EmeraldConn::new(channel, Credentials::jwt("jwt_overloaded"))
+            .with_retry(RetryPolicy::empty().with_method("/emerald.Market/*"));
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(request_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let _ = enable_tracing();
+        // Setup mock server
+        let addr: SocketAddr = "127.0.0.1:9121".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![],
+        };
+
+        let channel = start_server(addr, mock_service).await;
+
+        let policy = RateLimitPolicy::default()
+            .with_method("/emerald.Auth/*", RateLimit::per_second(2))
+            .with_max_queue(1);
+        let conn = EmeraldConn::new(channel.clone(), Credentials::jwt("jwt_static"))
+            .with_rate_limit(policy);
+
+        // two calls are made at once, the third one waits, and the fourth one doesn't fit into the queue
+        let started = std::time::Instant::now();
+        let calls = (0..4).map(|_| {
+            let mut client = connect(&conn);
+            async move { client.who_am_i(WhoAmIRequest {}).await }
+        });
+        let results = futures::future::join_all(calls).await;
+        assert!(started.elapsed() >= std::time::Duration::from_millis(400));
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 3);
+        let status = results.into_iter().find_map(|r| r.err()).unwrap();
+        let err = Error::from_status(&status);
+        assert!(matches!(err, Some(Error::RateLimited(_))), "error: {:?}", err);
+        assert_eq!(request_count.load(Ordering::Relaxed), 3);
+
+        let budget = conn.rate_budget();
+        assert_eq!(budget.len(), 1);
+        assert_eq!(budget[0].scope, "/emerald.Auth/*");
+        assert_eq!(budget[0].rate, 2.0);
+        assert_eq!(budget[0].queued, 0);
+
+        // the server is out of quota, so the client slows down
+        let conn = EmeraldConn::new(channel, Credentials::jwt("jwt_exhausted"))
+            .with_rate_limit(RateLimitPolicy::default().with_global(RateLimit::per_second(10)));
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        let budget = conn.rate_budget();
+        assert!(budget[0].rate < 5.1, "rate: {}", budget[0].rate);
+        assert!(budget[0].available < 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let _ = enable_tracing();
+        let addr: SocketAddr = "127.0.0.1:9127".parse().unwrap();
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let mock_service = MockAuthService {
+            request_count: request_count.clone(),
+            response_pos: Arc::new(AtomicUsize::new(0)),
+            responses: vec![],
+        };
+        start_server(addr, mock_service).await;
+
+        let conn = EmeraldConn::builder()
+            .with_endpoint(format!("http://{}", addr))
+            .with_plaintext()
+            .with_credentials(Credentials::jwt("jwt_slow"))
+            .with_request_timeout(std::time::Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        assert_eq!(request_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_endpoints() {
+        let _ = enable_tracing();
+        let first: SocketAddr = "127.0.0.1:9129".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:9130".parse().unwrap();
+        let first_count = Arc::new(AtomicUsize::new(0));
+        let second_count = Arc::new(AtomicUsize::new(0));
+        for (addr, request_count) in [(first, first_count.clone()), (second, second_count.clone())] {
+            start_server(addr, MockAuthService {
+                request_count,
+                response_pos: Arc::new(AtomicUsize::new(0)),
+                responses: vec![],
+            }).await;
+        }
+
+        let conn = EmeraldConn::builder()
+            .with_endpoints(vec![format!("http://{}", first), format!("http://{}", second)])
+            .with_plaintext()
+            .with_selection(Selection::RoundRobin)
+            .with_credentials(Credentials::jwt("jwt_static"))
+            .build()
+            .unwrap();
+        let mut client = connect(&conn);
+        for _ in 0..4 {
+            client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        }
+        assert_eq!(first_count.load(Ordering::Relaxed), 2);
+        assert_eq!(second_count.load(Ordering::Relaxed), 2);
+
+        // nothing listens on the first one. it's excluded after the first failed call, long before the next health check
+        let conn = EmeraldConn::builder()
+            .with_endpoints(vec!["http://127.0.0.1:9131".to_string(), format!("http://{}", first)])
+            .with_plaintext()
+            .with_selection(Selection::RoundRobin)
+            .with_health_check(std::time::Duration::from_secs(3600), std::time::Duration::from_secs(1))
+            .with_credentials(Credentials::jwt("jwt_static"))
+            .build()
+            .unwrap();
+        let mut client = connect(&conn);
+        assert!(client.who_am_i(WhoAmIRequest {}).await.is_err());
+        for _ in 0..3 {
+            client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        }
+        assert_eq!(first_count.load(Ordering::Relaxed), 5);
+    }
+
+}
@@ -0,0 +1,508 @@
+#[cfg(all(feature = "client-auth", feature = "server-auth-memory"))]
+mod on_memory {
+    use emerald_api::{
+        auth::connect,
+        conn::EmeraldConn,
+        creds::{Credentials, CredentialsEvent},
+        memory_auth::{MemoryAuthService, STATUS_DENIED},
+        balance::Selection,
+        policy::{AuthPolicy, AuthRequirement},
+        token_manager::TokenManager,
+        verify::{JwtVerifier, JwtVerifyLayer, VerificationKey},
+        errors::{CredentialsError, Error},
+        proto::auth::{
+            auth_server::AuthServer,
+            DeleteTokenRequest, IssueTokenRequest, ListTokensRequest, WhoAmIRequest,
+        },
+    };
+    use emerald_api::provider::{CredentialsProvider, LiteralProvider, ProvidedCredentials};
+    use tonic::{transport::{Channel, Server}, Request};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use chrono::Utc;
+
+    ///
+    /// Start the service on the specified address and connect to it
+    async fn start_server(addr: SocketAddr, service: MemoryAuthService) -> Channel {
+        tokio::spawn(async move {
+            let serve_future = Server::builder()
+                .add_service(AuthServer::new(service))
+                .serve(addr)
+                .await;
+            if let Err(e) = serve_future {
+                eprintln!("Failed to start server: {}", e);
+            }
+        });
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+        Channel::from_shared(format!("http://{}", addr)).unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_manage_tokens() {
+        let addr: SocketAddr = "127.0.0.1:9103".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let conn = EmeraldConn::new(channel.clone(), Credentials::token("secret_token"));
+        let mut client = connect(&conn);
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(who.is_authenticated);
+        assert_eq!(who.user_id, "user_001");
+        assert_eq!(conn.claims().unwrap().subject, Some("user_001".to_string()));
+        // same host, so the same clock
+        assert_eq!(conn.clock_offset(), chrono::Duration::zero());
+
+        let issued = client.issue_token(IssueTokenRequest {
+            display_name: "test".to_string(),
+            scopes: vec!["read".to_string()],
+            ..Default::default()
+        }).await.unwrap().into_inner();
+
+        let tokens = client.list_tokens(ListTokensRequest::default()).await.unwrap().into_inner().tokens;
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].token_id, issued.token_id);
+        assert_eq!(tokens[1].display_name, "test");
+
+        // the issued token can be used to authenticate, and gives only its own scopes
+        let issued_conn = EmeraldConn::new(channel.clone(), Credentials::token(&issued.access_token));
+        let who = connect(&issued_conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+        assert_eq!(issued_conn.claims().unwrap().scopes, vec!["read".to_string()]);
+
+        let deleted = client.delete_token(DeleteTokenRequest {
+            token_id: issued.token_id,
+            ..Default::default()
+        }).await.unwrap().into_inner();
+        assert!(deleted.deleted);
+
+        let deleted_conn = EmeraldConn::new(channel.clone(), Credentials::token(&issued.access_token));
+        let mut events = deleted_conn.subscribe();
+        let status = connect(&deleted_conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        let error = Error::from_status(&status);
+        assert!(matches!(error, Some(Error::Credentials(CredentialsError::Denied { status: STATUS_DENIED, .. }))));
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Denied { .. })));
+
+        let empty_conn = EmeraldConn::new(channel, Credentials::token(""));
+        let status = connect(&empty_conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        let error = Error::from_status(&status);
+        assert_eq!(error, Some(&Error::Credentials(CredentialsError::MissingSecret)));
+        assert_eq!(CredentialsError::from_status(&status), Some(&CredentialsError::MissingSecret));
+        assert_eq!(error.unwrap().to_string(), "Credentials error: No secret to authenticate with");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_issued_jwt() {
+        let addr: SocketAddr = "127.0.0.1:9104".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_access_lifetime(Duration::from_secs(5))
+            .with_token("secret_token", "user_001", vec![]);
+        let channel = start_server(addr, service).await;
+
+        // the JWT expires earlier than the refresh-ahead time, so it's used only for the first half of its lifetime
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"));
+        let mut events = conn.subscribe();
+        let mut client = connect(&conn);
+        client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Authenticated { .. })));
+        client.who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(who.is_authenticated);
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Refreshed { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_short_jwt_in_background() {
+        let addr: SocketAddr = "127.0.0.1:9122".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_access_lifetime(Duration::from_secs(2))
+            .with_token("secret_token", "user_001", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"))
+            .with_background_refresh();
+        let mut events = conn.subscribe();
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let mut refreshed = 0;
+        while let Ok(event) = events.try_recv() {
+            if matches!(event, CredentialsEvent::Refreshed { .. }) {
+                refreshed += 1;
+            }
+        }
+        // once per second, i.e., in the middle of the JWT lifetime
+        assert!((2..=6).contains(&refreshed), "refreshed: {}", refreshed);
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotate_token() {
+        let addr: SocketAddr = "127.0.0.1:9105".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let conn = EmeraldConn::new(channel.clone(), Credentials::token("secret_token"));
+        let manager = TokenManager::new(&conn);
+        let expires_at = Utc::now() + chrono::Duration::days(30);
+        let issued = manager.issue("ci", Some(expires_at), vec!["read".to_string()]).await.unwrap();
+
+        let conn = EmeraldConn::new(channel, Credentials::token(issued.secret.expose()));
+        let manager = TokenManager::new(&conn);
+        let rotated = manager.rotate(&issued.id).await.unwrap();
+        assert!(rotated.revoked);
+        assert_ne!(rotated.token.id, issued.id);
+
+        // the connection already uses the new token
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+
+        let tokens = manager.list().await.unwrap();
+        assert_eq!(tokens.len(), 2);
+        let token = tokens.iter().find(|token| token.id == rotated.token.id).unwrap();
+        assert_eq!(token.name, "ci");
+        assert_eq!(token.scopes, vec!["read".to_string()]);
+        assert!(token.expires_at.is_some_and(|ts| ts >= expires_at));
+        assert!(!tokens.iter().any(|token| token.id == issued.id));
+
+        // the provider would give the old secret again, so it's not rotated
+        let conn = conn.clone().with_credentials(Credentials::provider(LiteralProvider::new(ProvidedCredentials::Secret(rotated.token.secret.clone()))));
+        let err = TokenManager::new(&conn).rotate(&rotated.token.id).await.unwrap_err();
+        assert!(matches!(err, Error::Credentials(CredentialsError::Provider(_))));
+        assert_eq!(manager.list().await.unwrap().len(), 2);
+
+        let err = manager.rotate("unknown").await.unwrap_err();
+        assert_eq!(err, Error::TokenNotFound("unknown".to_string()));
+        let err = manager.issue("old", Some(Utc::now() - chrono::Duration::days(365 * 60)), vec![]).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        assert_eq!(manager.list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_scopes() {
+        let addr: SocketAddr = "127.0.0.1:9106".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec!["market:read".to_string(), "market:write".to_string()]);
+        let channel = start_server(addr, service).await;
+
+        let credentials = Credentials::token("secret_token")
+            .with_scopes(vec!["market:read".to_string()])
+            .with_agent("test/1.0");
+        let conn = EmeraldConn::new(channel.clone(), credentials);
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert_eq!(conn.claims().unwrap().scopes, vec!["market:read".to_string()]);
+
+        // not allowed for the token
+        let credentials = Credentials::token("secret_token")
+            .with_scopes(vec!["market:read".to_string(), "admin".to_string()]);
+        let conn = EmeraldConn::new(channel.clone(), credentials);
+        let mut events = conn.subscribe();
+        connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Authenticated { .. })));
+        assert_eq!(events.try_recv(), Ok(CredentialsEvent::ScopesNotGranted { missing: vec!["admin".to_string()] }));
+
+        let credentials = Credentials::token("secret_token")
+            .with_scopes(vec!["market:read".to_string(), "admin".to_string()])
+            .require_scopes();
+        let conn = EmeraldConn::new(channel, credentials);
+        let status = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        let error = Error::from_status(&status);
+        assert_eq!(error, Some(&Error::Credentials(CredentialsError::ScopesNotGranted(vec!["admin".to_string()]))));
+    }
+
+    #[tokio::test]
+    async fn test_credentials_per_request() {
+        let addr: SocketAddr = "127.0.0.1:9107".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![])
+            .with_token("secret_token_2", "user_002", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"));
+        let tenant = conn.credentials_handle(Credentials::token("secret_token_2"));
+        let mut client = connect(&conn);
+
+        let who = client.who_am_i(tenant.attach(Request::new(WhoAmIRequest {}))).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_002");
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+
+        // each keeps its own JWT
+        assert_eq!(conn.claims().unwrap().subject, Some("user_001".to_string()));
+        assert_eq!(tenant.get_credentials().claims().unwrap().subject, Some("user_002".to_string()));
+
+        // works for a connection without its own credentials too
+        let unauthenticated = EmeraldConn::new((&conn).into(), Credentials::unauthenticated());
+        let who = connect(&unauthenticated).who_am_i(tenant.attach(Request::new(WhoAmIRequest {}))).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_002");
+    }
+
+    #[tokio::test]
+    async fn test_replace_credentials() {
+        let addr: SocketAddr = "127.0.0.1:9108".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![])
+            .with_token("secret_token_2", "user_002", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"));
+        let mut client = connect(&conn);
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+
+        conn.set_credentials(Credentials::token("secret_token_2"));
+        assert_eq!(conn.claims(), None);
+
+        // the client created before uses the new credentials
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_002");
+    }
+
+    ///
+    /// Provides the secret with a delay, to replace the credentials while it authenticates
+    #[derive(Debug)]
+    struct SlowProvider {
+        secret: String,
+        delay: Duration,
+    }
+
+    #[tonic::async_trait]
+    impl CredentialsProvider for SlowProvider {
+        async fn credentials(&self) -> Result<ProvidedCredentials, CredentialsError> {
+            tokio::time::sleep(self.delay).await;
+            Ok(ProvidedCredentials::Secret(self.secret.clone().into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replace_credentials_during_authentication() {
+        let addr: SocketAddr = "127.0.0.1:9124".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![])
+            .with_token("secret_token_2", "user_002", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let conn = EmeraldConn::new(channel, Credentials::provider(SlowProvider {
+            secret: "secret_token".to_string(),
+            delay: Duration::from_millis(500),
+        }));
+        let mut client = connect(&conn);
+        let in_flight = tokio::spawn({
+            let mut client = client.clone();
+            async move { client.who_am_i(WhoAmIRequest {}).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        conn.set_credentials(Credentials::provider(LiteralProvider::new(ProvidedCredentials::Secret("secret_token_2".into()))));
+
+        // the call started before uses the JWT it waited for, but it's not kept for the new credentials
+        let who = in_flight.await.unwrap().unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+        match conn.get_credentials() {
+            Credentials::Provided { state, .. } => assert!(state.is_none()),
+            _ => panic!("Unexpected credentials"),
+        }
+
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_002");
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_behind_verify_layer() {
+        let addr: SocketAddr = "127.0.0.1:9128".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        let layer = JwtVerifyLayer::new(JwtVerifier::new(vec![VerificationKey::hmac(b"signing_secret")]))
+            .with_policy(AuthPolicy::empty().with_all(emerald_api::auth::AUTH_POLICY));
+        tokio::spawn(async move {
+            let serve_future = Server::builder()
+                .layer(layer)
+                .add_service(AuthServer::new(service))
+                .serve(addr)
+                .await;
+            if let Err(e) = serve_future {
+                eprintln!("Failed to start server: {}", e);
+            }
+        });
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        let channel = Channel::from_shared(format!("http://{}", addr)).unwrap().connect().await.unwrap();
+
+        // Authenticate is public, and WhoAmI is called with the verified JWT
+        let conn = EmeraldConn::new(channel.clone(), Credentials::token("secret_token"));
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+
+        // other methods still need the JWT
+        let status = connect(&EmeraldConn::new(channel, Credentials::None)).who_am_i(WhoAmIRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_optional_auth() {
+        let addr: SocketAddr = "127.0.0.1:9109".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        let channel = start_server(addr, service).await;
+
+        let policy = AuthPolicy::default()
+            .with("/emerald.Auth/WhoAmI", AuthRequirement::Optional);
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"))
+            .with_auth_policy(policy);
+        let mut events = conn.subscribe();
+        let mut client = connect(&conn);
+
+        // doesn't authenticate just for that
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(!who.is_authenticated);
+        assert!(events.try_recv().is_err());
+        assert_eq!(conn.claims(), None);
+
+        // but uses the JWT once it's there
+        client.list_tokens(ListTokensRequest::default()).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Authenticated { .. })));
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(who.is_authenticated);
+        assert_eq!(who.user_id, "user_001");
+
+        let conn = conn.with_auth_policy(AuthPolicy::default().with("/emerald.Auth/*", AuthRequirement::Public));
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(!who.is_authenticated);
+        // a client created before keeps the previous policy
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(who.is_authenticated);
+    }
+
+    #[tokio::test]
+    async fn test_separate_auth_server() {
+        // the API server knows nothing about the token, and only verifies the JWT issued by the auth server
+        let auth_addr: SocketAddr = "127.0.0.1:9110".parse().unwrap();
+        let auth_service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        let auth_channel = start_server(auth_addr, auth_service).await;
+        let api_addr: SocketAddr = "127.0.0.1:9111".parse().unwrap();
+        let channel = start_server(api_addr, MemoryAuthService::new(b"signing_secret")).await;
+
+        let conn = EmeraldConn::new(channel.clone(), Credentials::token("secret_token"));
+        assert!(connect(&conn).who_am_i(WhoAmIRequest {}).await.is_err());
+
+        let conn = EmeraldConn::new(channel, Credentials::token("secret_token"))
+            .with_auth_channel(Channel::from_shared(format!("http://{}", auth_addr)).unwrap().connect_lazy());
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert!(who.is_authenticated);
+        assert_eq!(who.user_id, "user_001");
+
+        // never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:9112").await.unwrap();
+        tokio::spawn(async move {
+            let mut accepted = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted.push(socket);
+            }
+        });
+        let conn = EmeraldConn::new(auth_channel, Credentials::token("secret_token"))
+            .with_auth_channel(Channel::from_static("http://127.0.0.1:9112").connect_lazy())
+            .with_auth_timeout(Duration::from_millis(500));
+        let mut events = conn.subscribe();
+        let started = std::time::Instant::now();
+        assert!(connect(&conn).who_am_i(WhoAmIRequest {}).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(events.try_recv(), Ok(CredentialsEvent::Failed { message: "Auth call timed out".to_string() }));
+
+        // a zero timeout is not the same as no timeout
+        let conn = EmeraldConn::new(Channel::from_shared(format!("http://{}", api_addr)).unwrap().connect_lazy(), Credentials::token("secret_token"))
+            .with_auth_channel(Channel::from_shared(format!("http://{}", auth_addr)).unwrap().connect_lazy())
+            .with_auth_timeout(Duration::ZERO);
+        let mut events = conn.subscribe();
+        assert!(connect(&conn).who_am_i(WhoAmIRequest {}).await.is_err());
+        assert_eq!(events.try_recv(), Ok(CredentialsEvent::Failed { message: "Auth call timed out".to_string() }));
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_builder() {
+        let addr: SocketAddr = "127.0.0.1:9113".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        start_server(addr, service).await;
+
+        let conn = EmeraldConn::builder()
+            .with_endpoint(format!("http://{}", addr))
+            .with_plaintext()
+            .with_credentials(Credentials::token("secret_token"))
+            .with_connect_timeout(Duration::from_secs(1))
+            .with_request_timeout(Duration::from_secs(5))
+            .with_keepalive(Duration::from_secs(10), Duration::from_secs(5))
+            .with_concurrency_limit(4)
+            .with_window_sizes(1 << 20, 1 << 22)
+            .with_user_agent("test/1.0")
+            .connect().await
+            .unwrap();
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+    }
+
+    #[tokio::test]
+    async fn test_share_jwt_between_endpoints() {
+        let addrs: Vec<SocketAddr> = vec!["127.0.0.1:9116".parse().unwrap(), "127.0.0.1:9117".parse().unwrap()];
+        for addr in &addrs {
+            let service = MemoryAuthService::new(b"signing_secret")
+                .with_token("secret_token", "user_001", vec![]);
+            start_server(*addr, service).await;
+        }
+
+        let conn = EmeraldConn::builder()
+            .with_endpoints(addrs.iter().map(|addr| format!("http://{}", addr)).collect())
+            .with_plaintext()
+            .with_credentials(Credentials::token("secret_token"))
+            .connect().await
+            .unwrap();
+        let mut events = conn.subscribe();
+        let mut client = connect(&conn);
+        for _ in 0..10 {
+            let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+            assert_eq!(who.user_id, "user_001");
+        }
+        assert!(matches!(events.try_recv(), Ok(CredentialsEvent::Authenticated { .. })));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_secondary_endpoint() {
+        let primary: SocketAddr = "127.0.0.1:9118".parse().unwrap();
+        let secondary: SocketAddr = "127.0.0.1:9119".parse().unwrap();
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        start_server(secondary, service).await;
+
+        // the primary is not started yet.
+        // the connection itself is dropped right away, but the client still gets the health checks
+        let mut client = connect(&EmeraldConn::builder()
+            .with_endpoints(vec![format!("http://{}", primary), format!("http://{}", secondary)])
+            .with_plaintext()
+            .with_selection(Selection::Priority)
+            .with_health_check(Duration::from_millis(200), Duration::from_millis(200))
+            .with_credentials(Credentials::token("secret_token"))
+            .connect().await
+            .unwrap());
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+
+        // the primary is back, and it's used again after the next check.
+        // it signs the JWT with another key and knows the token as another user, so it's clear which one responds
+        let service = MemoryAuthService::new(b"primary_secret")
+            .with_token("secret_token", "user_002", vec![]);
+        start_server(primary, service).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        // the JWT from the secondary is rejected, and the client authenticates again with the primary
+        client.list_tokens(ListTokensRequest::default()).await.unwrap();
+        let who = client.who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_002");
+    }
+}
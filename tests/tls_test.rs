@@ -0,0 +1,150 @@
+#[cfg(all(feature = "client-auth", feature = "server-auth-memory"))]
+mod on_tls {
+    use emerald_api::{
+        auth::connect,
+        builder::EmeraldConnBuilder,
+        conn::EmeraldConn,
+        creds::Credentials,
+        memory_auth::MemoryAuthService,
+        proto::auth::{auth_server::AuthServer, WhoAmIRequest},
+    };
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+    use std::net::SocketAddr;
+
+    ///
+    /// A certificate with its private key, in PEM format
+    struct Pem {
+        cert: String,
+        key: String,
+    }
+
+    ///
+    /// Generated certificates: a CA, a server certificate for `emerald.test`, and a client certificate, both signed by the CA
+    struct TestCerts {
+        ca: Pem,
+        server: Pem,
+        client: Pem,
+    }
+
+    fn generate_certs() -> TestCerts {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "Emerald Test CA");
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let mut server_params = CertificateParams::new(vec!["emerald.test".to_string()]).unwrap();
+        server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server_cert = server_params.signed_by(&server_key, &ca_cert, &ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        client_params.distinguished_name.push(DnType::CommonName, "test-client");
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_cert = client_params.signed_by(&client_key, &ca_cert, &ca_key).unwrap();
+
+        TestCerts {
+            ca: Pem { cert: ca_cert.pem(), key: ca_key.serialize_pem() },
+            server: Pem { cert: server_cert.pem(), key: server_key.serialize_pem() },
+            client: Pem { cert: client_cert.pem(), key: client_key.serialize_pem() },
+        }
+    }
+
+    ///
+    /// Start the service with TLS which requires a client certificate signed by the CA
+    async fn start_server(addr: SocketAddr, certs: &TestCerts) {
+        let tls = ServerTlsConfig::new()
+            .identity(Identity::from_pem(&certs.server.cert, &certs.server.key))
+            .client_ca_root(Certificate::from_pem(&certs.ca.cert));
+        let service = MemoryAuthService::new(b"signing_secret")
+            .with_token("secret_token", "user_001", vec![]);
+        let server = Server::builder().tls_config(tls).unwrap()
+            .add_service(AuthServer::new(service));
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(addr).await {
+                eprintln!("Failed to start server: {}", e);
+            }
+        });
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    }
+
+    fn builder(addr: SocketAddr) -> EmeraldConnBuilder {
+        EmeraldConn::builder()
+            .with_endpoint(format!("https://{}", addr))
+            .with_credentials(Credentials::token("secret_token"))
+            .with_native_roots(false)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let addr: SocketAddr = "127.0.0.1:9114".parse().unwrap();
+        let certs = generate_certs();
+        start_server(addr, &certs).await;
+
+        let conn = builder(addr)
+            .with_ca_certificate(&certs.ca.cert)
+            .with_client_identity(&certs.client.cert, &certs.client.key)
+            .with_tls_domain("emerald.test")
+            .build()
+            .unwrap();
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+
+        // no client certificate
+        let conn = builder(addr)
+            .with_ca_certificate(&certs.ca.cert)
+            .with_tls_domain("emerald.test")
+            .build()
+            .unwrap();
+        assert!(connect(&conn).who_am_i(WhoAmIRequest {}).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_server() {
+        let addr: SocketAddr = "127.0.0.1:9115".parse().unwrap();
+        let certs = generate_certs();
+        start_server(addr, &certs).await;
+
+        // the certificate is for another name
+        let conn = builder(addr)
+            .with_ca_certificate(&certs.ca.cert)
+            .with_client_identity(&certs.client.cert, &certs.client.key)
+            .with_tls_domain("other.test")
+            .build()
+            .unwrap();
+        assert!(connect(&conn).who_am_i(WhoAmIRequest {}).await.is_err());
+
+        // the certificate is signed by an unknown CA
+        let other = generate_certs();
+        let conn = builder(addr)
+            .with_ca_certificate(&other.ca.cert)
+            .with_client_identity(&certs.client.cert, &certs.client.key)
+            .with_tls_domain("emerald.test")
+            .build()
+            .unwrap();
+        assert!(connect(&conn).who_am_i(WhoAmIRequest {}).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auth_endpoint_with_same_tls() {
+        let addr: SocketAddr = "127.0.0.1:9125".parse().unwrap();
+        let auth_addr: SocketAddr = "127.0.0.1:9126".parse().unwrap();
+        let certs = generate_certs();
+        start_server(addr, &certs).await;
+        start_server(auth_addr, &certs).await;
+
+        // the auth server requires the client certificate and is trusted only with the CA, same as the API
+        let conn = builder(addr)
+            .with_auth_endpoint(format!("https://{}", auth_addr))
+            .with_ca_certificate(&certs.ca.cert)
+            .with_client_identity(&certs.client.cert, &certs.client.key)
+            .with_tls_domain("emerald.test")
+            .build()
+            .unwrap();
+        let who = connect(&conn).who_am_i(WhoAmIRequest {}).await.unwrap().into_inner();
+        assert_eq!(who.user_id, "user_001");
+    }
+}